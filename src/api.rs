@@ -0,0 +1,395 @@
+//! Collection backend talking to the Docker Engine API directly instead of
+//! scraping the output of `docker stats`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response};
+use log::{debug, error, warn};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::{CollectorStats, INITIAL_BACKOFF, Sample};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Default socket of a local docker daemon
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// How often the container list is refreshed in streaming mode
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a request may take, so a daemon that stops answering fails the
+/// request (and is retried) instead of stalling the collector
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Address of the Docker Engine API
+#[derive(Clone, Debug)]
+pub(crate) enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+impl Endpoint {
+    /// Endpoint for an (already validated) DOCKER_HOST value, or the local socket
+    pub(crate) fn from_docker_host(host: Option<&str>) -> Self {
+        match host.and_then(|h| h.split_once("://")) {
+            Some(("tcp", addr)) => Endpoint::Tcp(addr.to_string()),
+            Some((_, path)) => Endpoint::Unix(PathBuf::from(path)),
+            None => Endpoint::Unix(PathBuf::from(DEFAULT_SOCKET)),
+        }
+    }
+
+    /// Issue a GET request on a fresh connection, failing if the response
+    /// headers take longer than [`REQUEST_TIMEOUT`]
+    async fn get(&self, path: &str) -> Result<Response<Body>, Error> {
+        tokio::time::timeout(REQUEST_TIMEOUT, self.request(path)).await
+            .map_err(|_| format!("GET {} timed out after {:?}", path, REQUEST_TIMEOUT))?
+    }
+
+    async fn request(&self, path: &str) -> Result<Response<Body>, Error> {
+        let mut sender = match self {
+            Endpoint::Unix(socket) => handshake(UnixStream::connect(socket).await?).await?,
+            Endpoint::Tcp(addr) => handshake(TcpStream::connect(addr).await?).await?,
+        };
+        let req = Request::get(path)
+            .header("Host", "docker")
+            .body(Body::empty())?;
+        let res = sender.send_request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("GET {} returned {}", path, res.status()).into());
+        }
+        Ok(res)
+    }
+
+    /// GET a path and deserialize the whole response body, which has to
+    /// arrive within [`REQUEST_TIMEOUT`] as well
+    pub(crate) async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, Error> {
+        let res = self.get(path).await?;
+        let body = tokio::time::timeout(REQUEST_TIMEOUT, hyper::body::to_bytes(res.into_body())).await
+            .map_err(|_| format!("Reading the response to GET {} timed out after {:?}", path, REQUEST_TIMEOUT))??;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+impl std::fmt::Display for Endpoint {
+    /// As a DOCKER_HOST URL, used for the `host` label
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Endpoint::Unix(socket) => write!(f, "unix://{}", socket.display()),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+async fn handshake<S>(stream: S) -> Result<hyper::client::conn::SendRequest<Body>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+    Ok(sender)
+}
+
+#[derive(Debug, Deserialize)]
+struct ContainerSummary {
+    #[serde(rename = "Id")]
+    id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ApiStats {
+    name: String,
+    id: String,
+    cpu_stats: CpuStats,
+    precpu_stats: CpuStats,
+    memory_stats: MemoryStats,
+    networks: HashMap<String, NetworkStats>,
+    blkio_stats: BlkioStats,
+    pids_stats: PidsStats,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CpuStats {
+    cpu_usage: CpuUsage,
+    system_cpu_usage: Option<u64>,
+    online_cpus: Option<u32>,
+    /// Missing for containers without a CFS quota on some daemons
+    throttling_data: Option<ThrottlingData>,
+}
+
+/// How often the CFS quota held the container back, counted since it started
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ThrottlingData {
+    throttled_periods: u64,
+    /// Nanoseconds
+    throttled_time: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CpuUsage {
+    total_usage: u64,
+    percpu_usage: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MemoryStats {
+    usage: Option<u64>,
+    limit: Option<u64>,
+    stats: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct NetworkStats {
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BlkioStats {
+    io_service_bytes_recursive: Option<Vec<BlkioEntry>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BlkioEntry {
+    major: u64,
+    minor: u64,
+    op: String,
+    value: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PidsStats {
+    current: Option<u64>,
+}
+
+impl ApiStats {
+    /// True once the daemon has a previous CPU reading to compute a delta from
+    fn has_precpu(&self) -> bool {
+        self.precpu_stats.system_cpu_usage.unwrap_or(0) > 0
+    }
+
+    /// Convert to a sample labeled with `host`, using the same formulas as `docker stats`
+    fn to_sample(&self, host: &str) -> Sample {
+        let cpu_delta = self.cpu_stats.cpu_usage.total_usage as f64 - self.precpu_stats.cpu_usage.total_usage as f64;
+        let system_delta = self.cpu_stats.system_cpu_usage.unwrap_or(0) as f64
+            - self.precpu_stats.system_cpu_usage.unwrap_or(0) as f64;
+        let online_cpus = self.cpu_stats.online_cpus
+            .or_else(|| self.cpu_stats.cpu_usage.percpu_usage.as_ref().map(|p| p.len() as u32))
+            .unwrap_or(1);
+        let cpu = if cpu_delta > 0.0 && system_delta > 0.0 {
+            cpu_delta / system_delta * online_cpus as f64 * 100.0
+        } else {
+            0.0
+        };
+
+        // Like the CLI, don't count reclaimable page cache as used memory
+        // (`total_inactive_file` on cgroup v1, `inactive_file` on v2)
+        let usage = self.memory_stats.usage.unwrap_or(0);
+        let cache = self.memory_stats.stats.get("total_inactive_file")
+            .or_else(|| self.memory_stats.stats.get("inactive_file"))
+            .copied()
+            .filter(|&cache| cache < usage)
+            .unwrap_or(0);
+        // cgroup v1 names first, then v2, which has no swap figure
+        let stat = |names: &[&str]| names.iter().find_map(|name| self.memory_stats.stats.get(*name).copied());
+
+        let (net_in, net_out) = self.networks.values()
+            .fold((0, 0), |(rx, tx), n| (rx + n.rx_bytes, tx + n.tx_bytes));
+        let net_interfaces: BTreeMap<String, (u64, u64)> = self.networks.iter()
+            .map(|(interface, n)| (interface.clone(), (n.rx_bytes, n.tx_bytes)))
+            .collect();
+        let mut block_devices: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for e in self.blkio_stats.io_service_bytes_recursive.iter().flatten() {
+            let (read, write) = match e.op.to_ascii_lowercase().as_str() {
+                "read" => (e.value, 0),
+                "write" => (0, e.value),
+                _ => continue,
+            };
+            let device = block_devices.entry(format!("{}:{}", e.major, e.minor)).or_default();
+            device.0 += read;
+            device.1 += write;
+        }
+        let (block_read, block_write) = block_devices.values()
+            .fold((0, 0), |(r, w), &(read, write)| (r + read, w + write));
+
+        Sample {
+            name: self.name.trim_start_matches('/').to_string(),
+            id: self.id.chars().take(12).collect(),
+            cpu,
+            mem_usage: usage - cache,
+            mem_limit: self.memory_stats.limit.filter(|&limit| limit > 0),
+            mem_percent: self.memory_stats.limit.filter(|&limit| limit > 0).map(|limit| (usage - cache) as f64 / limit as f64 * 100.0),
+            mem_cache: stat(&["total_cache", "cache", "file"]),
+            mem_rss: stat(&["total_rss", "rss", "anon"]),
+            mem_swap: stat(&["total_swap", "swap"]),
+            net_in,
+            net_out,
+            block_read,
+            block_write,
+            pids: self.pids_stats.current.unwrap_or(0),
+            cpu_throttled_periods: self.cpu_stats.throttling_data.as_ref().map(|t| t.throttled_periods),
+            cpu_throttled_seconds: self.cpu_stats.throttling_data.as_ref().map(|t| t.throttled_time as f64 / 1e9),
+            net_interfaces,
+            block_devices,
+            host: host.to_string(),
+            read_at: Some(Utc::now()),
+            ..Sample::default()
+        }
+    }
+}
+
+async fn list_containers(endpoint: &Endpoint) -> Result<Vec<String>, Error> {
+    let containers: Vec<ContainerSummary> = endpoint.get_json("/containers/json").await?;
+    Ok(containers.into_iter().map(|c| c.id).collect())
+}
+
+/// Follow the stats stream of one container until it stops
+async fn stream_container(endpoint: Endpoint, host: &str, id: String, tx: mpsc::Sender<Sample>, stats: &CollectorStats) -> Result<(), Error> {
+    let mut res = endpoint.get(&format!("/containers/{}/stats?stream=true", id)).await?;
+    let mut buf = Vec::new();
+    while let Some(chunk) = res.body_mut().data().await {
+        buf.extend_from_slice(&chunk?);
+        while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            match serde_json::from_slice::<ApiStats>(&line) {
+                // The first frame has no previous CPU reading, skip it like the CLI does
+                Ok(frame) if !frame.has_precpu() => {}
+                Ok(frame) => {
+                    if tx.send(frame.to_sample(host)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    stats.parse_errors.inc();
+                    warn!("Invalid stats from Docker API for {}: {}", id, e);
+                    debug!("Invalid stats: {}", String::from_utf8_lossy(&line));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetch one stats snapshot of each of `ids` and forward them to `tx`.
+/// Failures of single containers are counted in `stats` and skipped.
+/// Returns `None` once `tx` is closed.
+async fn poll_containers(endpoint: &Endpoint, host: &str, ids: &[String], tx: &mpsc::Sender<Sample>, stats: &CollectorStats) -> Option<()> {
+    let polls = ids.iter().map(|id| async move {
+        endpoint.get_json::<ApiStats>(&format!("/containers/{}/stats?stream=false", id)).await
+    });
+    for frame in futures::future::join_all(polls).await {
+        match frame {
+            Ok(frame) => tx.send(frame.to_sample(host)).await.ok()?,
+            Err(e) => {
+                if e.is::<serde_json::Error>() {
+                    stats.parse_errors.inc();
+                } else {
+                    stats.scrape_errors.inc();
+                }
+                warn!("Polling container stats failed: {}", e);
+            }
+        }
+    }
+    Some(())
+}
+
+/// Collect a single stats snapshot of all running containers for `--once`,
+/// labeled with `host`. Returns whether the container list could be fetched.
+pub(crate) async fn collect_once(endpoint: Endpoint, host: String, tx: mpsc::Sender<Sample>, stats: CollectorStats) -> bool {
+    match list_containers(&endpoint).await {
+        Ok(ids) => {
+            poll_containers(&endpoint, &host, &ids, &tx, &stats).await;
+            true
+        }
+        Err(e) => {
+            error!("Docker API request to {} failed ({})", endpoint, e);
+            false
+        }
+    }
+}
+
+/// Per-container stream tasks, aborted when the supervisor goes away
+#[derive(Default)]
+struct StreamTasks(HashMap<String, JoinHandle<()>>);
+impl Drop for StreamTasks {
+    fn drop(&mut self) {
+        for task in self.0.values() {
+            task.abort();
+        }
+    }
+}
+
+/// Collect stats of all running containers from the Docker Engine API and
+/// forward them to `tx`, labeled with `host` (empty for the default daemon, like
+/// the CLI backend). In streaming mode one stats stream per container is
+/// kept open and new containers are picked up every few seconds; with an
+/// `interval` every container is polled once per interval instead.
+/// Unreachable daemons are retried with the same backoff as the CLI backend,
+/// stats that can't be deserialized and failed requests are counted in `stats`.
+pub(crate) async fn supervise_api(
+    endpoint: Endpoint,
+    host: String,
+    tx: mpsc::Sender<Sample>,
+    interval: Option<Duration>,
+    max_backoff: Duration,
+    stats: CollectorStats,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut streams = StreamTasks::default();
+    loop {
+        match list_containers(&endpoint).await {
+            Ok(ids) => {
+                backoff = INITIAL_BACKOFF;
+                match interval {
+                    Some(interval) => {
+                        if poll_containers(&endpoint, &host, &ids, &tx, &stats).await.is_none() {
+                            return;
+                        }
+                        tokio::time::sleep(interval).await;
+                    }
+                    None => {
+                        streams.0.retain(|_, task| !task.is_finished());
+                        for id in ids {
+                            if streams.0.contains_key(&id) {
+                                continue;
+                            }
+                            let (endpoint, host, tx, task_id, stats) = (endpoint.clone(), host.clone(), tx.clone(), id.clone(), stats.clone());
+                            let task = tokio::spawn(async move {
+                                if let Err(e) = stream_container(endpoint, &host, task_id.clone(), tx, &stats).await {
+                                    stats.scrape_errors.inc();
+                                    warn!("Stats stream for container {} failed: {}", task_id, e);
+                                }
+                            });
+                            streams.0.insert(id, task);
+                        }
+                        tokio::time::sleep(DISCOVERY_INTERVAL).await;
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                stats.scrape_errors.inc();
+                error!("Docker API request to {} failed ({}), retrying in {:?}", endpoint, e, backoff);
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
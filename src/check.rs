@@ -0,0 +1,163 @@
+//! `--check`: a preflight that tries the configured docker source and
+//! target once and prints what worked, instead of starting the exporter.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::config::{Bind, Config};
+use crate::sink::{GraphiteSink, InfluxApi, JsonlSink, StatsdSink};
+use crate::{CollectorStats, DockerTarget, api, collect_once, replay_stats};
+
+/// Time allowed for each single check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run all checks, print one line per check to stdout and return whether all passed
+pub(crate) async fn run(config: &Config) -> bool {
+    let mut results = Vec::new();
+    match &config.stats_source {
+        Some(path) => results.push((format!("stats source {}", path), check_source(config, None).await)),
+        None => {
+            for docker in config.docker_targets() {
+                let name = match docker.host.as_deref() {
+                    Some(host) => format!("docker {}", host),
+                    None => "docker (default)".to_string(),
+                };
+                results.push((name, check_source(config, Some(docker)).await));
+            }
+        }
+    }
+    // Each target is checked on its own
+    for target in config.targets() {
+        results.push((format!("target {}", target), check_target(config, target).await));
+    }
+
+    for (name, result) in &results {
+        match result {
+            Ok(summary) => println!("ok      {}: {}", name, summary),
+            Err(e) => println!("FAILED  {}: {}", name, e),
+        }
+    }
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+/// Collect one snapshot from `docker` (the stats source if `None`) and count the containers
+async fn check_source(config: &Config, docker: Option<DockerTarget>) -> Result<String, String> {
+    let (tx, mut rx) = mpsc::channel(1024);
+    let stats = CollectorStats::new(&config.metric_prefix);
+    let collector = match docker {
+        None => tokio::spawn(replay_stats(config.stats_source.clone().unwrap_or_default(), tx, stats, config.stats_format())),
+        Some(docker) if config.backend == "api" => {
+            tokio::spawn(api::collect_once(api::Endpoint::from_docker_host(docker.host.as_deref()), docker.name(), tx, stats))
+        }
+        Some(docker) => tokio::spawn(collect_once(docker, tx, stats, config.stats_format(), None)),
+    };
+    let count = async {
+        let mut containers = 0;
+        while rx.recv().await.is_some() {
+            containers += 1;
+        }
+        containers
+    };
+    let containers = tokio::time::timeout(CHECK_TIMEOUT, count).await
+        .map_err(|_| format!("no complete snapshot within {:?}", CHECK_TIMEOUT))?;
+    // The collectors log the details of a failure
+    if !collector.await.unwrap_or(false) {
+        return Err("collection failed, see the log above".to_string());
+    }
+    match containers {
+        0 => Err("no containers reported".to_string()),
+        n => Ok(format!("{} containers", n)),
+    }
+}
+
+/// Make sure `target` could be written to (or served from)
+async fn check_target(config: &Config, target: &str) -> Result<String, String> {
+    let http = reqwest::Client::builder().timeout(CHECK_TIMEOUT).build().map_err(|e| e.to_string())?;
+    match target {
+        "prometheus" if config.unix_socket.is_some() => {
+            let path = config.unix_socket.as_deref().unwrap_or_default();
+            if let Ok(meta) = std::fs::symlink_metadata(path) {
+                use std::os::unix::fs::FileTypeExt;
+                if !meta.file_type().is_socket() {
+                    return Err(format!("{} exists and is not a socket", path));
+                }
+                if tokio::net::UnixStream::connect(path).await.is_ok() {
+                    return Err(format!("{} is in use", path));
+                }
+                return Ok(format!("unix://{} is a stale socket and will be replaced", path));
+            }
+            drop(tokio::net::UnixListener::bind(path).map_err(|e| format!("cannot listen on {}: {}", path, e))?);
+            let _ = std::fs::remove_file(path);
+            Ok(format!("unix://{} can be bound", path))
+        }
+        "prometheus" => {
+            // Held until all are bound, like the servers themselves
+            let mut listeners = Vec::new();
+            for ip in config.bind_addrs() {
+                let addr = SocketAddr::new(ip, config.port);
+                match tokio::net::TcpListener::bind(addr).await {
+                    Ok(listener) => listeners.push((addr, listener)),
+                    Err(_) if config.bind == Bind::Any => {}
+                    Err(e) => return Err(format!("cannot bind {}: {}", addr, e)),
+                }
+            }
+            match listeners.is_empty() {
+                true => Err(format!("cannot bind port {} of any address", config.port)),
+                false => Ok(format!("{} can be bound", listeners.iter().map(|(addr, _)| addr.to_string()).collect::<Vec<_>>().join(", "))),
+            }
+        }
+        "influxdb" => {
+            // Served by 1.x and 2.x alike, without authentication
+            let url = format!("{}/ping", config.influx_url());
+            let http = InfluxApi::http_client(config.influx_ca_cert.as_deref(), config.influx_insecure)?;
+            let res = http.get(&url).timeout(CHECK_TIMEOUT).send().await.map_err(|e| format!("GET {}: {}", url, e))?;
+            match res.status() {
+                status if status.is_success() => Ok(format!("GET {}: {}", url, status)),
+                status => Err(format!("GET {}: {}", url, status)),
+            }
+        }
+        "pushgateway" => {
+            let url = config.pushgateway_url.as_deref().unwrap_or_default();
+            let res = http.head(url).send().await.map_err(|e| format!("HEAD {}: {}", url, e))?;
+            match res.status() {
+                status if status.is_success() => Ok(format!("HEAD {}: {}", url, status)),
+                status => Err(format!("HEAD {}: {}", url, status)),
+            }
+        }
+        "statsd" => {
+            StatsdSink::connect(&config.statsd_host, config.statsd_port, &[], &[]).await
+                .map_err(|e| format!("{}:{}: {}", config.statsd_host, config.statsd_port, e))?;
+            Ok(format!("{}:{} resolved (UDP, delivery not checked)", config.statsd_host, config.statsd_port))
+        }
+        "otlp" | "remote-write" => {
+            // A connection is all that can be checked without sending data
+            let endpoint = match target {
+                "otlp" => config.otlp_endpoint.as_deref(),
+                _ => config.remote_write_url.as_deref(),
+            }.unwrap_or_default();
+            let url = reqwest::Url::parse(endpoint).map_err(|e| format!("{}: {}", endpoint, e))?;
+            let (host, port) = (url.host_str().unwrap_or_default(), url.port_or_known_default().unwrap_or_default());
+            tokio::time::timeout(CHECK_TIMEOUT, tokio::net::TcpStream::connect((host, port))).await
+                .map_err(|_| format!("connecting to {}:{} timed out", host, port))?
+                .map_err(|e| format!("{}:{}: {}", host, port, e))?;
+            Ok(format!("{}:{} accepts connections", host, port))
+        }
+        "graphite" => {
+            GraphiteSink::connect(&config.graphite_host, config.graphite_port, &[], &[]).await
+                .map_err(|e| format!("{}:{}: {}", config.graphite_host, config.graphite_port, e))?;
+            Ok(format!("{}:{} accepts connections", config.graphite_host, config.graphite_port))
+        }
+        "jsonl" => {
+            JsonlSink::open(&config.output, 0, &[], &[]).await.map_err(|e| format!("{}: {}", config.output, e))?;
+            Ok(format!("{} is writable", config.output))
+        }
+        #[cfg(feature = "kafka")]
+        "kafka" => {
+            let (brokers, topic) = (config.kafka_brokers.as_deref().unwrap_or_default(), config.kafka_topic.as_deref().unwrap_or_default());
+            crate::sink::check_brokers(brokers, topic, CHECK_TIMEOUT).await
+        }
+        target => Err(format!("unknown target {}", target)),
+    }
+}
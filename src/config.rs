@@ -0,0 +1,1182 @@
+//! Runtime options, from the command line and an optional TOML config file.
+//!
+//! Every option `--foo-bar VALUE` has a matching `foo_bar` key in the config
+//! file (`filters` takes a list of `--filter` values, `relabel` a list of
+//! `--relabel` rules, `node_labels` a list of `--node-label` values,
+//! `disable_metrics` a list of `--disable-metric` names, `label_map` a list
+//! of `--label-map` values,
+//! `docker_host` one or a list of `--docker-host` values).
+//! Every option can also be set as a `DME_FOO_BAR` environment variable.
+//! Command line options override the environment, which overrides the
+//! file, which overrides the `INFLUXDB_TOKEN` and `DOCKER_HOST` environment
+//! variables, which override the defaults.
+
+use std::collections::HashSet;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::sink::{InfluxPrecision, InfluxTimestamp};
+use crate::{CpuMode, DockerTarget, Locale, StatsFormat};
+
+/// What `--target` accepts, several joined with `+`
+const TARGETS: [&str; 9] = ["prometheus", "influxdb", "statsd", "graphite", "jsonl", "pushgateway", "remote-write", "otlp", "kafka"];
+
+/// Print usage information
+pub(crate) fn usage() {
+    eprintln!("Usage: docker_metrics_exporter [--config FILE] [--target prometheus|influxdb|statsd|graphite|jsonl|pushgateway|remote-write|otlp|kafka]... [-p PORT] [--host HOST] [--db DB]");
+    eprintln!("  --config FILE            Read options from a TOML file, command line options take precedence");
+    eprintln!("                           Each option can also be set as DME_OPTION_NAME, e.g. DME_INFLUX_TOKEN");
+    eprintln!("  --target TARGET          prometheus (default), influxdb, statsd, graphite, jsonl, pushgateway, remote-write, otlp or kafka (repeatable, or joined with +)");
+    eprintln!("  -p, --port PORT          Port for HTTP (Prometheus) or InfluxDB server (default: 9187)");
+    eprintln!("  --bind ADDR              Address the HTTP (Prometheus) endpoint listens on, IPv4, IPv6 or * for both (default: 0.0.0.0)");
+    eprintln!("  --host HOST              InfluxDB host, https://HOST for TLS (default: localhost)");
+    eprintln!("  --influx-port PORT       InfluxDB port with both --target prometheus and influxdb, where -p is the HTTP port (default: 8086)");
+    eprintln!("  --db DB                  InfluxDB database (default: metrics)");
+    eprintln!("  --unix-socket PATH       Serve the HTTP (Prometheus) endpoint on a Unix domain socket instead of TCP");
+    eprintln!("  --metrics-path PATH      Path the metrics are served on (default: /metrics)");
+    eprintln!("  --tls-cert FILE          Serve the metrics over HTTPS with this PEM certificate (needs --tls-key)");
+    eprintln!("  --tls-key FILE           PEM private key for --tls-cert");
+    eprintln!("  --metrics-user USER      Require HTTP basic auth for the metrics (needs --metrics-password)");
+    eprintln!("  --metrics-password PASS  Password for --metrics-user");
+    eprintln!("  --influx-token TOKEN     InfluxDB API token (default: $INFLUXDB_TOKEN)");
+    eprintln!("  --influx-org ORG         InfluxDB 2.x organization (needs --influx-bucket and a token)");
+    eprintln!("  --influx-bucket BUCKET   InfluxDB 2.x bucket, written via /api/v2/write instead of --db");
+    eprintln!("  --influx-user USER       InfluxDB username");
+    eprintln!("  --influx-password PASS   InfluxDB password");
+    eprintln!("  --influx-ca-cert FILE    Also trust this PEM CA certificate for an https:// InfluxDB --host");
+    eprintln!("  --influx-insecure        Don't verify the certificate of an https:// InfluxDB --host");
+    eprintln!("  --influx-compress        Send InfluxDB writes gzip-compressed");
+    eprintln!("  --influx-precision P     Timestamp precision of the InfluxDB points: ms, us or ns (default: ns)");
+    eprintln!("  --influx-timestamp T     Timestamp the points with when the stats were read or written: read or write (default: read)");
+    eprintln!("  --statsd-host HOST       StatsD/DogStatsD host (default: localhost)");
+    eprintln!("  --statsd-port PORT       StatsD/DogStatsD UDP port (default: 8125)");
+    eprintln!("  --graphite-host HOST     Graphite host (default: localhost)");
+    eprintln!("  --graphite-port PORT     Graphite plaintext TCP port (default: 2003)");
+    eprintln!("  --pushgateway-url URL    Pushgateway to push to, e.g. http://localhost:9091");
+    eprintln!("  --job NAME               Pushgateway job (default: docker_metrics_exporter)");
+    eprintln!("  --instance NAME          Pushgateway instance grouping key (default: this hostname, empty to omit)");
+    eprintln!("  --remote-write-url URL   Prometheus remote-write receiver, e.g. http://localhost:9009/api/v1/push");
+    eprintln!("  --otlp-endpoint URL      OpenTelemetry collector to export to, e.g. http://localhost:4317");
+    eprintln!("  --otlp-protocol PROTO    grpc (default) or http (protobuf, e.g. http://localhost:4318)");
+    eprintln!("  --kafka-brokers LIST     Kafka brokers to publish to, comma separated host:port (needs the kafka feature)");
+    eprintln!("  --kafka-topic TOPIC      Kafka topic of the JSON messages, keyed by container name");
+    eprintln!("  --output PATH            JSON lines file to append to, - for stdout (default: -)");
+    eprintln!("  --max-file-bytes N       Rotate the JSON lines file when it would exceed N bytes (default: 0, never)");
+    eprintln!("  --flush-interval SECS    Flush and fsync the JSON lines file every SECS (default: 1)");
+    eprintln!("  --filter REGEX           Only export containers whose name matches (repeatable, OR-ed)");
+    eprintln!("  --relabel 'REGEX=>REPL'  Rewrite container names, $1 etc. refer to capture groups (repeatable, applied in order)");
+    eprintln!("  --node-label KEY=VALUE   Static label added to every series, e.g. datacenter=eu-west (repeatable)");
+    eprintln!("  --disable-metric NAME    Don't export NAME, e.g. block_read or net_output (repeatable; Prometheus, Pushgateway, InfluxDB)");
+    eprintln!("  --dedup-names            Suffix the name of running containers sharing a name with the start of their id");
+    eprintln!("  --label-map DOCKER=LABEL Export the docker label DOCKER as LABEL, e.g. com.example.team=team (repeatable)");
+    eprintln!("  --labels LIST            Extra labels, comma separated: host, image, compose_project, compose_service, network_mode from docker inspect");
+    eprintln!("  --batch-size N           Max points per InfluxDB write (default: 500)");
+    eprintln!("  --batch-interval MS      Max time before pending points are written (default: 1000)");
+    eprintln!("  --measurement NAME       InfluxDB measurement of the container points (default: docker_stats)");
+    eprintln!("  --dead-letter-file PATH  Append InfluxDB batches that failed 3 writes to PATH as line protocol");
+    eprintln!("  --backend cli|api        Collect via `docker stats` (default) or the Docker Engine API");
+    eprintln!("  --stats-format TEMPLATE  Go template for `docker stats --format`, one JSON object per line (default: {{{{json .}}}})");
+    eprintln!("  --raw-stats              Print every line read from `docker stats` to stderr, escaped, for debugging");
+    eprintln!("  --locale auto|dot|comma  Decimal separator in `docker stats` output (default: auto, guessed per value)");
+    eprintln!("  --cpu-mode MODE          raw: CPU % as docker reports it (default), normalized: divided by the container's CPUs");
+    eprintln!("  --cpu-max-clamp PERCENT  Cap CPU readings above PERCENT (default: 100 per host CPU)");
+    eprintln!("  --smooth ALPHA           Also export docker_cpu_percent_smoothed, an EWMA with weight ALPHA (0 < ALPHA <= 1)");
+    eprintln!("  --docker-host URL        Docker daemon to scrape, unix://, tcp:// or ssh:// (default: $DOCKER_HOST, repeatable)");
+    eprintln!("  --docker-context NAME    Docker CLI context to use");
+    eprintln!("  --runtime-binary BIN     CLI run for stats and inspect, e.g. podman or nerdctl (default: docker)");
+    eprintln!("  --ssh USER@HOST          Run the CLI on HOST over ssh, for daemons without an exposed socket");
+    eprintln!("  --ssh-identity KEYFILE   Private key for --ssh (default: ssh's own choice)");
+    eprintln!("  --stats-source FILE      Read recorded `docker stats` JSON lines from FILE (- for stdin) instead of running docker");
+    eprintln!("  --interval SECS          Poll with `docker stats --no-stream` every SECS instead of streaming");
+    eprintln!("  --max-series N           Export at most N containers (label sets), drop samples of further ones (default: no limit)");
+    eprintln!("  --cardinality-alert-rate N");
+    eprintln!("                           Warn if more than N new containers (label sets) show up within a minute");
+    eprintln!("  --stale-timeout SECS     Drop series of containers not seen for SECS (default: 60, at least 3x --interval)");
+    eprintln!("  --include-stopped        Also report stopped containers, with all metrics 0 and docker_container_running 0");
+    eprintln!("  --health-timeout SECS    /healthz reports stale if no stats were read for SECS (default: 60)");
+    eprintln!("  --max-backoff SECS       Max delay between docker stats restarts (default: 30)");
+    eprintln!("  --startup-jitter SECS    Wait a random time of up to SECS before starting, for fleets started at once");
+    eprintln!("  --seed N                 Seed for --startup-jitter, the same seed gives the same delay");
+    eprintln!("  --max-runtime SECS       Flush and exit after collecting for SECS, e.g. for a CI job");
+    eprintln!("  --watchdog-timeout SECS  Kill and restart docker stats if it prints nothing for SECS (default: off)");
+    eprintln!("  --no-self-metrics        Don't export the process_* metrics of the exporter itself");
+    eprintln!("  --once                   Collect one `docker stats --no-stream` snapshot, write or print it and exit");
+    eprintln!("  --top N                  With --once, print a table of the N busiest containers instead of the exposition");
+    eprintln!("  --sort-by cpu|mem        What --top orders by (default: cpu)");
+    eprintln!("  --check                  Check that docker and the target are reachable, print a summary and exit");
+    eprintln!("  --metric-prefix PREFIX   Prefix of the Prometheus metric names (default: docker)");
+    eprintln!("  --log-level LEVEL        error, warn, info (default), debug or trace; RUST_LOG takes precedence");
+    eprintln!("  --quiet                  Log only warnings and errors, without the startup banner");
+    eprintln!("  -V, --version            Show the version, git commit and build time");
+    eprintln!("  -h, --help               Show this help");
+}
+
+/// Address of the HTTP (Prometheus) endpoint, `--bind`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) enum Bind {
+    /// IPv4 or IPv6, `::` is dual-stack where the OS makes it so
+    Ip(IpAddr),
+    /// `*`: both `::` and `0.0.0.0`
+    Any,
+}
+impl FromStr for Bind {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "*" => Ok(Bind::Any),
+            // IPv6 literals may be bracketed like in URLs
+            s => s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s).parse().map(Bind::Ip).map_err(|_| ()),
+        }
+    }
+}
+impl TryFrom<String> for Bind {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse().map_err(|_| format!("invalid bind address {}, expected an IPv4 or IPv6 address or *", s))
+    }
+}
+
+/// InfluxDB's own default port, for `--influx-port`
+const DEFAULT_INFLUX_PORT: u16 = 8086;
+
+/// All options, as read from the config file and the command line
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// One or more of [`TARGETS`], joined with `+`
+    #[serde(deserialize_with = "joined_targets")]
+    pub(crate) target: String,
+    pub(crate) port: u16,
+    pub(crate) bind: Bind,
+    pub(crate) host: String,
+    pub(crate) db: String,
+    pub(crate) unix_socket: Option<String>,
+    pub(crate) metrics_path: String,
+    pub(crate) tls_cert: Option<String>,
+    pub(crate) tls_key: Option<String>,
+    pub(crate) metrics_user: Option<String>,
+    pub(crate) metrics_password: Option<String>,
+    pub(crate) influx_token: Option<String>,
+    pub(crate) influx_org: Option<String>,
+    pub(crate) influx_bucket: Option<String>,
+    pub(crate) influx_user: Option<String>,
+    pub(crate) influx_password: Option<String>,
+    pub(crate) influx_ca_cert: Option<String>,
+    pub(crate) influx_insecure: bool,
+    pub(crate) influx_compress: bool,
+    pub(crate) influx_precision: InfluxPrecision,
+    pub(crate) influx_timestamp: InfluxTimestamp,
+    /// `port` is used unless both Prometheus and InfluxDB are targets
+    pub(crate) influx_port: Option<u16>,
+    pub(crate) statsd_host: String,
+    pub(crate) statsd_port: u16,
+    pub(crate) graphite_host: String,
+    pub(crate) graphite_port: u16,
+    pub(crate) pushgateway_url: Option<String>,
+    pub(crate) remote_write_url: Option<String>,
+    pub(crate) job: String,
+    /// The hostname if unset
+    pub(crate) instance: Option<String>,
+    pub(crate) otlp_endpoint: Option<String>,
+    pub(crate) otlp_protocol: String,
+    /// Comma separated `host:port`
+    pub(crate) kafka_brokers: Option<String>,
+    pub(crate) kafka_topic: Option<String>,
+    pub(crate) output: String,
+    pub(crate) max_file_bytes: u64,
+    /// Seconds
+    pub(crate) flush_interval: f64,
+    #[serde(alias = "filter")]
+    pub(crate) filters: Vec<String>,
+    /// `REGEX=>REPLACEMENT` rules for the container names
+    pub(crate) relabel: Vec<String>,
+    /// `KEY=VALUE` labels added to every series
+    #[serde(alias = "node_label")]
+    pub(crate) node_labels: Vec<String>,
+    /// Names from [`crate::METRIC_NAMES`] not to export
+    #[serde(alias = "disable_metric")]
+    pub(crate) disable_metrics: Vec<String>,
+    /// Names from [`crate::EXTRA_LABELS`]
+    pub(crate) labels: Vec<String>,
+    /// `DOCKER_LABEL=METRIC_LABEL` docker labels to export
+    pub(crate) label_map: Vec<String>,
+    pub(crate) batch_size: usize,
+    /// Milliseconds
+    pub(crate) batch_interval: u64,
+    pub(crate) measurement: String,
+    pub(crate) dead_letter_file: Option<String>,
+    pub(crate) backend: String,
+    /// Passed to `docker stats --format`
+    pub(crate) stats_format: String,
+    /// Print the lines of `docker stats` as read
+    pub(crate) raw_stats: bool,
+    pub(crate) locale: Locale,
+    pub(crate) cpu_mode: CpuMode,
+    /// Percent, 100 × the host's CPUs if unset
+    pub(crate) cpu_max_clamp: Option<f64>,
+    /// Weight of the newest CPU reading in the EWMA, no smoothing if unset
+    pub(crate) smooth: Option<f64>,
+    /// A single URL or a list in the config file
+    #[serde(deserialize_with = "one_or_many")]
+    pub(crate) docker_host: Vec<String>,
+    pub(crate) docker_context: Option<String>,
+    /// Name in PATH or path of the docker compatible CLI
+    pub(crate) runtime_binary: String,
+    pub(crate) ssh: Option<String>,
+    pub(crate) ssh_identity: Option<String>,
+    /// Recorded stats to read instead of running docker, `-` for stdin
+    pub(crate) stats_source: Option<String>,
+    /// File to copy the raw `docker stats` lines to, not listed in the usage
+    pub(crate) record: Option<String>,
+    /// Seconds, streaming if unset
+    pub(crate) interval: Option<f64>,
+    /// Containers (label sets) the Prometheus gauges may hold, unlimited if unset
+    pub(crate) max_series: Option<usize>,
+    /// New label sets per minute above which the growth is warned about
+    pub(crate) cardinality_alert_rate: Option<usize>,
+    /// Seconds, derived from `interval` if unset
+    pub(crate) stale_timeout: Option<f64>,
+    /// Seconds
+    pub(crate) health_timeout: f64,
+    /// Seconds
+    pub(crate) max_backoff: u64,
+    /// Seconds, no watchdog if unset
+    pub(crate) watchdog_timeout: Option<f64>,
+    /// Seconds, start right away if unset
+    pub(crate) startup_jitter: Option<f64>,
+    pub(crate) max_runtime: Option<f64>,
+    pub(crate) seed: Option<u64>,
+    pub(crate) log_level: String,
+    /// Caps `log_level` at warnings
+    pub(crate) quiet: bool,
+    pub(crate) metric_prefix: String,
+    pub(crate) once: bool,
+    /// `--once` prints a table of this many containers
+    pub(crate) top: Option<usize>,
+    pub(crate) sort_by: String,
+    pub(crate) include_stopped: bool,
+    pub(crate) check: bool,
+    pub(crate) no_self_metrics: bool,
+    pub(crate) dedup_names: bool,
+    /// `-h`/`--help` was given
+    #[serde(skip)]
+    pub(crate) help: bool,
+    /// `-V`/`--version` was given
+    #[serde(skip)]
+    pub(crate) version: bool,
+    /// The `--config` file, read again by `POST /-/reload`
+    #[serde(skip)]
+    pub(crate) config_file: Option<String>,
+    /// Problems that don't stop the exporter, logged by `main` once the logger is set up
+    #[serde(skip)]
+    pub(crate) warnings: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            target: "prometheus".to_string(),
+            port: 9187,
+            bind: Bind::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            host: "localhost".to_string(),
+            db: "metrics".to_string(),
+            unix_socket: None,
+            metrics_path: "/metrics".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            metrics_user: None,
+            metrics_password: None,
+            influx_token: None,
+            influx_org: None,
+            influx_bucket: None,
+            influx_user: None,
+            influx_password: None,
+            influx_ca_cert: None,
+            influx_insecure: false,
+            influx_compress: false,
+            influx_precision: InfluxPrecision::Ns,
+            influx_timestamp: InfluxTimestamp::Read,
+            influx_port: None,
+            statsd_host: "localhost".to_string(),
+            statsd_port: 8125,
+            graphite_host: "localhost".to_string(),
+            graphite_port: 2003,
+            pushgateway_url: None,
+            remote_write_url: None,
+            job: "docker_metrics_exporter".to_string(),
+            instance: None,
+            otlp_endpoint: None,
+            otlp_protocol: "grpc".to_string(),
+            kafka_brokers: None,
+            kafka_topic: None,
+            output: "-".to_string(),
+            max_file_bytes: 0,
+            flush_interval: 1.0,
+            filters: Vec::new(),
+            relabel: Vec::new(),
+            node_labels: Vec::new(),
+            disable_metrics: Vec::new(),
+            labels: Vec::new(),
+            label_map: Vec::new(),
+            batch_size: 500,
+            batch_interval: 1000,
+            measurement: "docker_stats".to_string(),
+            dead_letter_file: None,
+            backend: "cli".to_string(),
+            stats_format: crate::DEFAULT_STATS_FORMAT.to_string(),
+            raw_stats: false,
+            locale: Locale::Auto,
+            cpu_mode: CpuMode::Raw,
+            cpu_max_clamp: None,
+            smooth: None,
+            docker_host: Vec::new(),
+            docker_context: None,
+            runtime_binary: "docker".to_string(),
+            ssh: None,
+            ssh_identity: None,
+            stats_source: None,
+            record: None,
+            interval: None,
+            max_series: None,
+            cardinality_alert_rate: None,
+            stale_timeout: None,
+            health_timeout: 60.0,
+            max_backoff: 30,
+            watchdog_timeout: None,
+            startup_jitter: None,
+            max_runtime: None,
+            seed: None,
+            log_level: "info".to_string(),
+            quiet: false,
+            metric_prefix: "docker".to_string(),
+            once: false,
+            top: None,
+            sort_by: "cpu".to_string(),
+            include_stopped: false,
+            check: false,
+            no_self_metrics: false,
+            dedup_names: false,
+            help: false,
+            version: false,
+            config_file: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// Options that take no value, set to `true` when given
+const SWITCHES: [&str; 9] = [
+    "once", "check", "include-stopped", "no-self-metrics", "raw-stats", "influx-insecure", "influx-compress", "dedup-names", "quiet",
+];
+
+/// Prefix of the environment variables that set options, like
+/// `DME_INFLUX_TOKEN` for `--influx-token`
+const ENV_PREFIX: &str = "DME_";
+
+/// Options set in the environment, as (variable, option name, value). A
+/// variable set to an empty value doesn't count, as if unset.
+fn env_options() -> Vec<(String, String, String)> {
+    let mut options: Vec<_> = env::vars()
+        .filter(|(_, value)| !value.is_empty())
+        .filter_map(|(var, value)| {
+            let key = var.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase().replace('_', "-");
+            Some((var, key, value))
+        })
+        .collect();
+    options.sort();
+    options
+}
+
+/// Error of [`Config::merge`] for a `key` that isn't an option
+fn unknown_option(key: &str) -> String {
+    format!("Unknown option --{}", key)
+}
+
+/// Parse an option value, naming the option on failure
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid --{} value: {}", key, value))
+}
+
+/// Accept `key = "value"` as well as `key = ["value", ...]`
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Accept `target = "influxdb"`, `target = "prometheus+influxdb"` and `target = ["prometheus", "influxdb"]`
+fn joined_targets<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(one_or_many(deserializer)?.join("+"))
+}
+
+/// Check that a DOCKER_HOST value looks like an address the docker CLI understands
+fn valid_docker_host(host: &str) -> bool {
+    match host.split_once("://") {
+        Some(("unix", path)) => path.starts_with('/'),
+        Some(("tcp", addr)) => addr.rsplit_once(':').is_some_and(|(h, p)| !h.is_empty() && p.parse::<u16>().is_ok()),
+        Some(("ssh", dest)) => !dest.is_empty(),
+        _ => false,
+    }
+}
+
+impl Config {
+    /// Resolve the options from the command line (without the program name),
+    /// the config file it names and the environment, then validate them
+    pub(crate) fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut help = false;
+        let mut version = false;
+        let mut options = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            let arg = args[i].as_str();
+            let key = match arg {
+                "-h" | "--help" => {
+                    help = true;
+                    i += 1;
+                    continue;
+                }
+                "-V" | "--version" => {
+                    version = true;
+                    i += 1;
+                    continue;
+                }
+                "-p" => "port",
+                _ => arg.strip_prefix("--").ok_or_else(|| format!("Unexpected argument: {}", arg))?,
+            };
+            if SWITCHES.contains(&key) {
+                options.push((key, "true"));
+                i += 1;
+                continue;
+            }
+            let value = args.get(i + 1).ok_or_else(|| format!("Missing value for {}", arg))?;
+            options.push((key, value.as_str()));
+            i += 2;
+        }
+        if help || version {
+            return Ok(Config { help, version, ..Config::default() });
+        }
+
+        // The command line over the environment over the config file
+        let env_options = env_options();
+        let config_file = options.iter().rev()
+            .find(|(key, _)| *key == "config")
+            .map(|(_, path)| path.to_string())
+            .or_else(|| env_options.iter().find(|(_, key, _)| key == "config").map(|(_, _, path)| path.clone()));
+        let mut config = match &config_file {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        for (var, key, value) in &env_options {
+            match config.merge(&[(key, value)]) {
+                Ok(()) => {}
+                // The prefix is short enough for other tools to use it too
+                Err(e) if e == unknown_option(key) => config.warnings.push(format!("Ignoring {}, --{} is not an option", var, key)),
+                Err(e) => return Err(format!("{}: {}", var, e)),
+            }
+        }
+        config.merge(&options)?;
+        config.config_file = config_file;
+        config.influx_token = config.influx_token.or_else(|| env::var("INFLUXDB_TOKEN").ok()).filter(|t| !t.is_empty());
+        if config.docker_host.is_empty() {
+            config.docker_host.extend(env::var("DOCKER_HOST").ok().filter(|h| !h.is_empty()));
+        }
+        let mut seen = HashSet::new();
+        config.docker_host.retain(|host| seen.insert(host.clone()));
+        // Samples of several hosts can't be told apart otherwise
+        if config.docker_host.len() > 1 && !config.labels.iter().any(|l| l == "host") {
+            config.labels.insert(0, "host".to_string());
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load a TOML config file, unset keys keep their defaults
+    fn from_file(path: &str) -> Result<Config, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path, e))
+    }
+
+    /// Override values with command line options, given as (name without
+    /// leading dashes, value) in command line order. Later options win,
+    /// except for `--filter`, `--relabel`, `--node-label`, `--disable-metric`,
+    /// `--label-map`, `--docker-host` and `--target`
+    /// which accumulate; values from
+    /// the command line replace those from the config file.
+    fn merge(&mut self, options: &[(&str, &str)]) -> Result<(), String> {
+        let mut cli_filters = Vec::new();
+        let mut cli_relabel = Vec::new();
+        let mut cli_node_labels = Vec::new();
+        let mut cli_disabled = Vec::new();
+        let mut cli_label_map = Vec::new();
+        let mut cli_hosts = Vec::new();
+        let mut cli_targets = Vec::new();
+        for &(key, value) in options {
+            match key {
+                "config" => {}
+                "target" => cli_targets.push(value),
+                "port" => self.port = parse(key, value)?,
+                "bind" => self.bind = parse(key, value)?,
+                "host" => self.host = value.to_string(),
+                "db" => self.db = value.to_string(),
+                "unix-socket" => self.unix_socket = Some(value.to_string()),
+                "metrics-path" => self.metrics_path = value.to_string(),
+                "tls-cert" => self.tls_cert = Some(value.to_string()),
+                "tls-key" => self.tls_key = Some(value.to_string()),
+                "metrics-user" => self.metrics_user = Some(value.to_string()),
+                "metrics-password" => self.metrics_password = Some(value.to_string()),
+                "influx-token" => self.influx_token = Some(value.to_string()),
+                "influx-org" => self.influx_org = Some(value.to_string()),
+                "influx-bucket" => self.influx_bucket = Some(value.to_string()),
+                "influx-user" => self.influx_user = Some(value.to_string()),
+                "influx-ca-cert" => self.influx_ca_cert = Some(value.to_string()),
+                "influx-insecure" => self.influx_insecure = parse(key, value)?,
+                "influx-compress" => self.influx_compress = parse(key, value)?,
+                "influx-password" => self.influx_password = Some(value.to_string()),
+                "influx-precision" => self.influx_precision = parse(key, value)?,
+                "influx-timestamp" => self.influx_timestamp = parse(key, value)?,
+                "influx-port" => self.influx_port = Some(parse(key, value)?),
+                "statsd-host" => self.statsd_host = value.to_string(),
+                "statsd-port" => self.statsd_port = parse(key, value)?,
+                "graphite-host" => self.graphite_host = value.to_string(),
+                "graphite-port" => self.graphite_port = parse(key, value)?,
+                "pushgateway-url" => self.pushgateway_url = Some(value.to_string()),
+                "remote-write-url" => self.remote_write_url = Some(value.to_string()),
+                "job" => self.job = value.to_string(),
+                "instance" => self.instance = Some(value.to_string()),
+                "kafka-brokers" => self.kafka_brokers = Some(value.to_string()),
+                "kafka-topic" => self.kafka_topic = Some(value.to_string()),
+                "output" => self.output = value.to_string(),
+                "max-file-bytes" => self.max_file_bytes = parse(key, value)?,
+                "flush-interval" => self.flush_interval = parse(key, value)?,
+                "filter" => cli_filters.push(value.to_string()),
+                "relabel" => cli_relabel.push(value.to_string()),
+                "node-label" => cli_node_labels.push(value.to_string()),
+                "disable-metric" => cli_disabled.push(value.to_string()),
+                "label-map" => cli_label_map.push(value.to_string()),
+                "labels" => self.labels = value.split(',').map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+                "batch-size" => self.batch_size = parse(key, value)?,
+                "batch-interval" => self.batch_interval = parse(key, value)?,
+                "measurement" => self.measurement = value.to_string(),
+                "dead-letter-file" => self.dead_letter_file = Some(value.to_string()),
+                "otlp-endpoint" => self.otlp_endpoint = Some(value.to_string()),
+                "otlp-protocol" => self.otlp_protocol = value.to_string(),
+                "backend" => self.backend = value.to_string(),
+                "stats-format" => self.stats_format = value.to_string(),
+                "raw-stats" => self.raw_stats = parse(key, value)?,
+                "locale" => self.locale = parse(key, value)?,
+                "cpu-mode" => self.cpu_mode = parse(key, value)?,
+                "cpu-max-clamp" => self.cpu_max_clamp = Some(parse(key, value)?),
+                "smooth" => self.smooth = Some(parse(key, value)?),
+                "docker-host" => cli_hosts.push(value.to_string()),
+                "docker-context" => self.docker_context = Some(value.to_string()),
+                "runtime-binary" => self.runtime_binary = value.to_string(),
+                "ssh" => self.ssh = Some(value.to_string()),
+                "ssh-identity" => self.ssh_identity = Some(value.to_string()),
+                "stats-source" => self.stats_source = Some(value.to_string()),
+                "record" => self.record = Some(value.to_string()),
+                "interval" => self.interval = Some(parse(key, value)?),
+                "max-series" => self.max_series = Some(parse(key, value)?),
+                "cardinality-alert-rate" => self.cardinality_alert_rate = Some(parse(key, value)?),
+                "stale-timeout" => self.stale_timeout = Some(parse(key, value)?),
+                "health-timeout" => self.health_timeout = parse(key, value)?,
+                "max-backoff" => self.max_backoff = parse(key, value)?,
+                "watchdog-timeout" => self.watchdog_timeout = Some(parse(key, value)?),
+                "startup-jitter" => self.startup_jitter = Some(parse(key, value)?),
+                "max-runtime" => self.max_runtime = Some(parse(key, value)?),
+                "seed" => self.seed = Some(parse(key, value)?),
+                "log-level" => self.log_level = value.to_string(),
+                "quiet" => self.quiet = parse(key, value)?,
+                "metric-prefix" => self.metric_prefix = value.to_string(),
+                "once" => self.once = parse(key, value)?,
+                "top" => self.top = Some(parse(key, value)?),
+                "sort-by" => self.sort_by = value.to_string(),
+                "include-stopped" => self.include_stopped = parse(key, value)?,
+                "check" => self.check = parse(key, value)?,
+                "no-self-metrics" => self.no_self_metrics = parse(key, value)?,
+                "dedup-names" => self.dedup_names = parse(key, value)?,
+                _ => return Err(unknown_option(key)),
+            }
+        }
+        if !cli_filters.is_empty() {
+            self.filters = cli_filters;
+        }
+        if !cli_relabel.is_empty() {
+            self.relabel = cli_relabel;
+        }
+        if !cli_node_labels.is_empty() {
+            self.node_labels = cli_node_labels;
+        }
+        if !cli_disabled.is_empty() {
+            self.disable_metrics = cli_disabled;
+        }
+        if !cli_label_map.is_empty() {
+            self.label_map = cli_label_map;
+        }
+        if !cli_hosts.is_empty() {
+            self.docker_host = cli_hosts;
+        }
+        if !cli_targets.is_empty() {
+            self.target = cli_targets.join("+");
+        }
+        Ok(())
+    }
+
+    /// Reject values that are well-typed but unusable, and conflicting options
+    fn validate(&self) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for target in self.targets() {
+            if !TARGETS.contains(&target) {
+                return Err(format!("Invalid --target value: '{}', expected one of {}", target, TARGETS.join(", ")));
+            }
+            if !seen.insert(target) {
+                return Err(format!("--target {} is given twice", target));
+            }
+        }
+        let positive = |secs: f64| secs > 0.0 && secs.is_finite();
+        if let Some(secs) = self.interval.filter(|&s| !positive(s)) {
+            return Err(format!("Invalid --interval value: {}", secs));
+        }
+        if let Some(secs) = self.stale_timeout.filter(|&s| !positive(s)) {
+            return Err(format!("Invalid --stale-timeout value: {}", secs));
+        }
+        if self.max_series == Some(0) {
+            return Err("Invalid --max-series value: 0".to_string());
+        }
+        if self.cardinality_alert_rate == Some(0) {
+            return Err("Invalid --cardinality-alert-rate value: 0".to_string());
+        }
+        if let Some(max) = self.cpu_max_clamp.filter(|&max| !positive(max)) {
+            return Err(format!("Invalid --cpu-max-clamp value: {}", max));
+        }
+        if let Some(alpha) = self.smooth.filter(|&alpha| !(alpha > 0.0 && alpha <= 1.0)) {
+            return Err(format!("Invalid --smooth value: {}, must be > 0 and <= 1", alpha));
+        }
+        if !positive(self.flush_interval) {
+            return Err(format!("Invalid --flush-interval value: {}", self.flush_interval));
+        }
+        if !positive(self.health_timeout) {
+            return Err(format!("Invalid --health-timeout value: {}", self.health_timeout));
+        }
+        if let Some(timeout) = self.watchdog_timeout.filter(|&timeout| !positive(timeout)) {
+            return Err(format!("Invalid --watchdog-timeout value: {}", timeout));
+        }
+        if self.top == Some(0) {
+            return Err("Invalid --top value: 0".to_string());
+        }
+        if self.sort_by != "cpu" && self.sort_by != "mem" {
+            return Err(format!("Invalid --sort-by value: {}", self.sort_by));
+        }
+        if self.top.is_some() && !(self.once && self.target == "prometheus") {
+            return Err("--top replaces the exposition printed by --once, it needs --once and --target prometheus".to_string());
+        }
+        if let Some(secs) = self.startup_jitter.filter(|&s| !(s >= 0.0 && s.is_finite())) {
+            return Err(format!("Invalid --startup-jitter value: {}", secs));
+        }
+        if self.seed.is_some() && self.startup_jitter.is_none() {
+            return Err("--seed only applies to --startup-jitter".to_string());
+        }
+        if let Some(secs) = self.max_runtime.filter(|&s| !(s > 0.0 && s.is_finite())) {
+            return Err(format!("Invalid --max-runtime value: {}", secs));
+        }
+        if self.max_runtime.is_some() && self.once {
+            return Err("--once exits after one snapshot, it can't be combined with --max-runtime".to_string());
+        }
+        if self.batch_size == 0 {
+            return Err("Invalid --batch-size value: 0".to_string());
+        }
+        // Names starting with `_` are reserved by InfluxDB, control characters can't be escaped
+        if self.measurement.is_empty() || self.measurement.starts_with('_') || self.measurement.contains(char::is_control) {
+            return Err(format!("Invalid --measurement value: {:?}", self.measurement));
+        }
+        if self.batch_interval == 0 {
+            return Err("Invalid --batch-interval value: 0".to_string());
+        }
+        if !Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap().is_match(&self.metric_prefix) {
+            return Err(format!("Invalid --metric-prefix value: {} (expected [a-zA-Z_][a-zA-Z0-9_]*)", self.metric_prefix));
+        }
+        if self.log_level.parse::<log::LevelFilter>().is_err() {
+            return Err(format!("Invalid --log-level value: {}", self.log_level));
+        }
+        for filter in &self.filters {
+            if let Err(e) = Regex::new(filter) {
+                return Err(format!("Invalid --filter regex '{}': {}", filter, e));
+            }
+        }
+        for rule in &self.relabel {
+            let Some((regex, _)) = rule.split_once("=>") else {
+                return Err(format!("Invalid --relabel rule '{}' (expected REGEX=>REPLACEMENT)", rule));
+            };
+            if let Err(e) = Regex::new(regex) {
+                return Err(format!("Invalid --relabel regex '{}': {}", regex, e));
+            }
+        }
+        if let Some(metric) = self.disable_metrics.iter().find(|m| !crate::METRIC_NAMES.contains(&m.as_str())) {
+            return Err(format!("Invalid --disable-metric value: {} (expected one of {})", metric, crate::METRIC_NAMES.join(", ")));
+        }
+        if let Some(label) = self.labels.iter().find(|l| !crate::EXTRA_LABELS.contains(&l.as_str())) {
+            return Err(format!("Invalid --labels value: {}", label));
+        }
+        let (label_key, label_value) = (Regex::new("^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap(), Regex::new("^[a-zA-Z0-9_.:/-]+$").unwrap());
+        let mut node_keys = HashSet::new();
+        for label in &self.node_labels {
+            let Some((key, value)) = label.split_once('=') else {
+                return Err(format!("Invalid --node-label value '{}' (expected KEY=VALUE)", label));
+            };
+            if !label_key.is_match(key) || key.starts_with("__") {
+                return Err(format!("Invalid --node-label key: {} (expected [a-zA-Z_][a-zA-Z0-9_]*, not starting with __)", key));
+            }
+            if !label_value.is_match(value) {
+                return Err(format!("Invalid --node-label value for {}: '{}' (expected [a-zA-Z0-9_.:/-]+)", key, value));
+            }
+            // Taken by the series themselves or the Pushgateway grouping key
+            let taken = ["name", "id", "interface", "device"].contains(&key)
+                || self.labels.iter().any(|l| l == key)
+                || (self.has_target("pushgateway") && (key == "job" || key == "instance"));
+            if taken {
+                return Err(format!("--node-label {} conflicts with a label the exporter sets itself", key));
+            }
+            if !node_keys.insert(key) {
+                return Err(format!("--node-label {} given more than once", key));
+            }
+        }
+        let mut mapped = HashSet::new();
+        for mapping in &self.label_map {
+            let Some((docker_label, label)) = mapping.split_once('=').filter(|(docker_label, _)| !docker_label.is_empty()) else {
+                return Err(format!("Invalid --label-map value '{}' (expected DOCKER_LABEL=METRIC_LABEL)", mapping));
+            };
+            if !label_key.is_match(label) || label.starts_with("__") {
+                return Err(format!("Invalid --label-map label for {}: {} (expected [a-zA-Z_][a-zA-Z0-9_]*, not starting with __)", docker_label, label));
+            }
+            let taken = ["name", "id", "interface", "device"].contains(&label)
+                || crate::EXTRA_LABELS.contains(&label)
+                || node_keys.contains(label)
+                || (self.has_target("pushgateway") && (label == "job" || label == "instance"));
+            if taken {
+                return Err(format!("--label-map label {} conflicts with a label the exporter sets itself", label));
+            }
+            if !mapped.insert(label) {
+                return Err(format!("--label-map label {} given more than once", label));
+            }
+        }
+        if self.backend != "cli" && self.backend != "api" {
+            return Err(format!("Invalid --backend value: {}", self.backend));
+        }
+
+        for host in &self.docker_host {
+            if !valid_docker_host(host) {
+                return Err(format!("Invalid docker host '{}': expected unix:///path/to/docker.sock, tcp://HOST:PORT or ssh://[USER@]HOST", host));
+            }
+            if self.backend == "api" && host.starts_with("ssh://") {
+                return Err(format!("--backend api cannot connect to {}: use --backend cli for ssh:// docker hosts", host));
+            }
+        }
+        if !self.docker_host.is_empty() && self.docker_context.is_some() {
+            return Err("--docker-context cannot be combined with --docker-host/DOCKER_HOST".to_string());
+        }
+        if self.has_target("pushgateway") {
+            let url = self.pushgateway_url.as_deref().ok_or("--target pushgateway requires --pushgateway-url")?;
+            if !reqwest::Url::parse(url).is_ok_and(|url| !url.cannot_be_a_base() && url.scheme().starts_with("http")) {
+                return Err(format!("Invalid --pushgateway-url value: {}", url));
+            }
+            if self.job.is_empty() {
+                return Err("--job must not be empty".to_string());
+            }
+        }
+        if self.has_target("remote-write") {
+            if !cfg!(feature = "remote-write") {
+                return Err("--target remote-write needs a build with the remote-write feature (cargo build --features remote-write)".to_string());
+            }
+            let url = self.remote_write_url.as_deref().ok_or("--target remote-write requires --remote-write-url")?;
+            if !reqwest::Url::parse(url).is_ok_and(|url| !url.cannot_be_a_base() && url.scheme().starts_with("http")) {
+                return Err(format!("Invalid --remote-write-url value: {}", url));
+            }
+        }
+        if self.has_target("otlp") {
+            let url = self.otlp_endpoint.as_deref().ok_or("--target otlp requires --otlp-endpoint")?;
+            if !reqwest::Url::parse(url).is_ok_and(|url| !url.cannot_be_a_base() && url.scheme().starts_with("http")) {
+                return Err(format!("Invalid --otlp-endpoint value: {}", url));
+            }
+            if self.otlp_protocol != "grpc" && self.otlp_protocol != "http" {
+                return Err(format!("Invalid --otlp-protocol value: {}", self.otlp_protocol));
+            }
+        }
+        if self.has_target("kafka") {
+            if !cfg!(feature = "kafka") {
+                return Err("--target kafka needs a build with the kafka feature (cargo build --features kafka)".to_string());
+            }
+            if self.kafka_brokers.as_deref().is_none_or(|brokers| brokers.split(',').all(|broker| broker.trim().is_empty())) {
+                return Err("--target kafka requires --kafka-brokers".to_string());
+            }
+            if self.kafka_topic.as_deref().is_none_or(str::is_empty) {
+                return Err("--target kafka requires --kafka-topic".to_string());
+            }
+        }
+        if self.backend == "api" && self.stats_source.is_some() {
+            return Err("--stats-source is only supported with --backend cli".to_string());
+        }
+        if self.include_stopped && self.stats_source.is_some() {
+            return Err("--include-stopped needs a docker daemon, it can't be used with --stats-source".to_string());
+        }
+        if self.cpu_mode == CpuMode::Normalized && self.stats_source.is_some() {
+            return Err("--cpu-mode normalized needs the CPU limits from docker inspect, it can't be used with --stats-source".to_string());
+        }
+        if self.record.is_some() && (self.backend == "api" || self.stats_source.is_some()) {
+            return Err("--record copies the output of docker stats, it needs --backend cli and no --stats-source".to_string());
+        }
+        if self.watchdog_timeout.is_some() && (self.backend == "api" || self.stats_source.is_some()) {
+            return Err("--watchdog-timeout watches the docker stats process, it needs --backend cli and no --stats-source".to_string());
+        }
+        if self.stats_format.trim().is_empty() {
+            return Err("Invalid --stats-format value: empty".to_string());
+        }
+        if self.backend == "api" && self.raw_stats {
+            return Err("--raw-stats is only supported with --backend cli".to_string());
+        }
+        if self.backend == "api" && self.stats_format != crate::DEFAULT_STATS_FORMAT {
+            return Err("--stats-format is only supported with --backend cli".to_string());
+        }
+        if self.runtime_binary.is_empty() {
+            return Err("Invalid --runtime-binary value: empty".to_string());
+        }
+        if self.backend == "api" && self.runtime_binary != "docker" {
+            return Err("--runtime-binary is only supported with --backend cli".to_string());
+        }
+        if self.backend == "api" && self.docker_context.is_some() {
+            return Err("--docker-context is only supported with --backend cli".to_string());
+        }
+        if let Some(destination) = &self.ssh {
+            if destination.is_empty() || destination.starts_with('-') {
+                return Err(format!("Invalid --ssh value: '{}', expected [USER@]HOST", destination));
+            }
+            if self.backend == "api" || self.stats_source.is_some() {
+                return Err("--ssh runs the docker CLI remotely, it needs --backend cli and no --stats-source".to_string());
+            }
+            if !self.docker_host.is_empty() || self.docker_context.is_some() {
+                return Err("--ssh cannot be combined with --docker-host/DOCKER_HOST or --docker-context".to_string());
+            }
+        }
+        if let Some(identity) = &self.ssh_identity {
+            if self.ssh.is_none() {
+                return Err("--ssh-identity requires --ssh".to_string());
+            }
+            if !std::path::Path::new(identity).is_file() {
+                return Err(format!("--ssh-identity file {} does not exist", identity));
+            }
+        }
+
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    if let Err(e) = std::fs::File::open(path) {
+                        return Err(format!("Cannot read TLS file {}: {}", path, e));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+        }
+        if self.influx_port.is_some() && !(self.serves_prometheus() && self.has_target("influxdb")) {
+            return Err("--influx-port is only used with both --target prometheus and influxdb, set the InfluxDB port with -p".to_string());
+        }
+        if !self.metrics_path.starts_with('/') || self.metrics_path == "/" || self.metrics_path.ends_with('/') || self.metrics_path.contains("//") {
+            return Err(format!("Invalid --metrics-path value: '{}', expected a path like /metrics or /probe/docker", self.metrics_path));
+        }
+        if self.metrics_path == "/healthz" || self.metrics_path == "/-/reload" {
+            return Err(format!("--metrics-path {} is taken by the endpoint of the same name", self.metrics_path));
+        }
+        if self.unix_socket.is_some() {
+            if !self.serves_prometheus() {
+                return Err("--unix-socket is only supported with --target prometheus".to_string());
+            }
+            if self.tls_cert.is_some() {
+                return Err("--unix-socket can't be combined with --tls-cert".to_string());
+            }
+        }
+        if self.influx_org.is_some() != self.influx_bucket.is_some() {
+            return Err("--influx-org and --influx-bucket must be given together".to_string());
+        }
+        match self.host.split_once("://") {
+            None | Some(("http" | "https", _)) => {}
+            Some((scheme, _)) => return Err(format!("Invalid --host value: {}, expected HOST, http://HOST or https://HOST", scheme)),
+        }
+        if (self.influx_ca_cert.is_some() || self.influx_insecure) && !self.host.starts_with("https://") {
+            return Err("--influx-ca-cert and --influx-insecure need an https:// InfluxDB --host".to_string());
+        }
+        if self.influx_ca_cert.is_some() && self.influx_insecure {
+            return Err("--influx-ca-cert has no effect with --influx-insecure".to_string());
+        }
+        if let Some(path) = &self.influx_ca_cert {
+            crate::sink::InfluxApi::http_client(Some(path), false)?;
+        }
+        if self.influx_bucket.is_some() && self.influx_token.is_none() {
+            return Err("--influx-bucket needs an API token (--influx-token or INFLUXDB_TOKEN)".to_string());
+        }
+        if self.metrics_user.is_some() != self.metrics_password.is_some() {
+            return Err("--metrics-user and --metrics-password must be given together".to_string());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.interval.map(Duration::from_secs_f64)
+    }
+
+    /// Defaults to 60s, but at least three poll intervals
+    pub(crate) fn stale_timeout(&self) -> Duration {
+        self.stale_timeout.map(Duration::from_secs_f64)
+            .unwrap_or_else(|| Duration::from_secs(60).max(self.interval().unwrap_or_default() * 3))
+    }
+
+    pub(crate) fn health_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.health_timeout)
+    }
+
+    pub(crate) fn flush_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.flush_interval)
+    }
+
+    /// Random delay of at most `--startup-jitter`, derived from `--seed` if given
+    pub(crate) fn startup_delay(&self) -> Duration {
+        let Some(max) = self.startup_jitter else {
+            return Duration::ZERO;
+        };
+        let fraction: f64 = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed).gen_range(0.0..=1.0),
+            None => rand::thread_rng().gen_range(0.0..=1.0),
+        };
+        Duration::from_secs_f64(max * fraction)
+    }
+
+    /// `--log-level`, at most `warn` with `--quiet`
+    pub(crate) fn log_filter(&self) -> log::LevelFilter {
+        let level = self.log_level.parse().unwrap_or(log::LevelFilter::Info);
+        match self.quiet {
+            true => level.min(log::LevelFilter::Warn),
+            false => level,
+        }
+    }
+
+    /// `--max-runtime` as a duration, `None` to run until stopped
+    pub(crate) fn max_runtime(&self) -> Option<Duration> {
+        self.max_runtime.map(Duration::from_secs_f64)
+    }
+
+    pub(crate) fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff.max(1))
+    }
+
+    pub(crate) fn batch_interval(&self) -> Duration {
+        Duration::from_millis(self.batch_interval)
+    }
+
+    pub(crate) fn filters(&self) -> Vec<Regex> {
+        // Already checked in `validate`
+        self.filters.iter().map(|f| Regex::new(f).unwrap()).collect()
+    }
+
+    /// The `--relabel` rules as (regex, replacement)
+    pub(crate) fn relabel(&self) -> Vec<(Regex, String)> {
+        // Already checked in `validate`
+        self.relabel.iter()
+            .filter_map(|rule| rule.split_once("=>"))
+            .map(|(regex, replacement)| (Regex::new(regex).unwrap(), replacement.to_string()))
+            .collect()
+    }
+
+    /// Addresses to listen on, see [`Bind`]
+    pub(crate) fn bind_addrs(&self) -> Vec<IpAddr> {
+        match self.bind {
+            Bind::Ip(ip) => vec![ip],
+            Bind::Any => vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED), IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+        }
+    }
+
+    /// The `--target`s, in the order given
+    pub(crate) fn targets(&self) -> Vec<&str> {
+        self.target.split('+').collect()
+    }
+
+    pub(crate) fn has_target(&self, target: &str) -> bool {
+        self.targets().contains(&target)
+    }
+
+    /// Whether the Prometheus endpoint is served, alone or next to other targets
+    pub(crate) fn serves_prometheus(&self) -> bool {
+        self.has_target("prometheus")
+    }
+
+    /// Base URL of the InfluxDB server, `-p` is the one of the endpoint next to Prometheus
+    pub(crate) fn influx_url(&self) -> String {
+        let port = match self.serves_prometheus() {
+            true => self.influx_port.unwrap_or(DEFAULT_INFLUX_PORT),
+            false => self.port,
+        };
+        match self.host.split_once("://") {
+            Some((scheme, host)) => format!("{}://{}:{}", scheme, host, port),
+            None => format!("http://{}:{}", self.host, port),
+        }
+    }
+
+    /// One target per `--docker-host`, or the CLI's default daemon
+    pub(crate) fn docker_targets(&self) -> Vec<DockerTarget> {
+        if self.docker_host.is_empty() {
+            return vec![DockerTarget {
+                binary: self.runtime_binary.clone(),
+                context: self.docker_context.clone(),
+                ssh: self.ssh.clone(),
+                ssh_identity: self.ssh_identity.clone(),
+                ..DockerTarget::default()
+            }];
+        }
+        self.docker_host.iter()
+            .map(|host| DockerTarget { binary: self.runtime_binary.clone(), host: Some(host.clone()), ..DockerTarget::default() })
+            .collect()
+    }
+
+    /// How `docker stats` output is requested and parsed
+    pub(crate) fn stats_format(&self) -> StatsFormat {
+        StatsFormat {
+            template: self.stats_format.clone(),
+            locale: self.locale,
+            raw: self.raw_stats,
+            watchdog: self.watchdog_timeout.map(Duration::from_secs_f64),
+        }
+    }
+
+    /// The `--node-label` labels as (key, value)
+    pub(crate) fn node_labels(&self) -> Vec<(String, String)> {
+        // Already checked in `validate`
+        self.node_labels.iter()
+            .filter_map(|label| label.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// The `--label-map` mappings as (docker label, metric label)
+    pub(crate) fn label_map(&self) -> Vec<(String, String)> {
+        // Already checked in `validate`
+        self.label_map.iter()
+            .filter_map(|mapping| mapping.split_once('='))
+            .map(|(docker_label, label)| (docker_label.to_string(), label.to_string()))
+            .collect()
+    }
+
+    /// Labels added to every container series after `name` and `id`: the
+    /// `--labels`, then the `--label-map` ones
+    pub(crate) fn extra_labels(&self) -> Vec<String> {
+        self.labels.iter().cloned().chain(self.label_map().into_iter().map(|(_, label)| label)).collect()
+    }
+
+    /// Whether anything exported comes from `docker inspect`: a `--labels`
+    /// entry other than `host`, `--label-map`, `--cpu-mode normalized` or a
+    /// not disabled one of [`crate::INSPECT_METRICS`]
+    pub(crate) fn needs_inspect(&self) -> bool {
+        self.labels.iter().any(|label| label != "host")
+            || !self.label_map.is_empty()
+            || self.cpu_mode == CpuMode::Normalized
+            || crate::INSPECT_METRICS.iter().any(|metric| !self.disable_metrics.iter().any(|m| m == metric))
+    }
+
+    /// Pushgateway instance grouping key, empty to push without one
+    pub(crate) fn instance(&self) -> String {
+        self.instance.clone().unwrap_or_else(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname").map(|h| h.trim().to_string()).unwrap_or_default()
+        })
+    }
+
+    /// The `--metrics-path` segments, `["probe", "docker"]` for `/probe/docker`
+    pub(crate) fn metrics_path_segments(&self) -> Vec<String> {
+        self.metrics_path.split('/').skip(1).map(str::to_string).collect()
+    }
+
+    /// Certificate and key path, if the metrics are served over HTTPS
+    pub(crate) fn tls(&self) -> Option<(String, String)> {
+        self.tls_cert.clone().zip(self.tls_key.clone())
+    }
+
+    /// Expected `Authorization` header value for the metrics
+    pub(crate) fn metrics_auth(&self) -> Option<String> {
+        use base64::Engine;
+        let (user, password) = self.metrics_user.as_ref().zip(self.metrics_password.as_ref())?;
+        Some(format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(options: &[(&str, &str)]) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.merge(options)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn later_options_win() {
+        let config = merged(&[("port", "9000"), ("interval", "5"), ("port", "9100")]).unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.interval, Some(5.0));
+    }
+
+    #[test]
+    fn list_options_accumulate_and_replace_the_file() {
+        let mut config = Config { filters: vec!["^from_file$".to_string()], ..Config::default() };
+        config.merge(&[("filter", "^web"), ("filter", "^db"), ("target", "prometheus"), ("target", "statsd")]).unwrap();
+        assert_eq!(config.filters, ["^web", "^db"]);
+        assert_eq!(config.targets(), ["prometheus", "statsd"]);
+        config.merge(&[("port", "9100")]).unwrap();
+        assert_eq!(config.filters, ["^web", "^db"]);
+    }
+
+    #[test]
+    fn bad_options_are_rejected() {
+        assert_eq!(merged(&[("no-such-option", "1")]).unwrap_err(), unknown_option("no-such-option"));
+        assert_eq!(merged(&[("port", "http")]).unwrap_err(), "Invalid --port value: http");
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_values() {
+        let invalid = |options: &[(&str, &str)]| merged(options).unwrap().validate().is_err();
+        assert!(invalid(&[("target", "prometheus"), ("target", "prometheus")]));
+        assert!(invalid(&[("target", "carrier-pigeon")]));
+        assert!(invalid(&[("interval", "0")]));
+        assert!(invalid(&[("max-series", "0")]));
+        assert!(invalid(&[("backend", "grpc")]));
+        assert!(invalid(&[("target", "pushgateway")]));
+        assert!(invalid(&[("target", "pushgateway"), ("pushgateway-url", "ftp://gateway:9091")]));
+    }
+
+    #[test]
+    fn docker_hosts_are_checked() {
+        let valid = |options: &[(&str, &str)]| merged(options).unwrap().validate().is_ok();
+        assert!(valid(&[("docker-host", "unix:///var/run/docker.sock")]));
+        assert!(valid(&[("docker-host", "tcp://10.0.0.5:2375")]));
+        assert!(valid(&[("docker-host", "ssh://me@box")]));
+        assert!(!valid(&[("docker-host", "ssh://me@box"), ("backend", "api")]));
+        assert!(!valid(&[("docker-host", "tcp://10.0.0.5")]));
+        assert!(!valid(&[("docker-host", "unix://docker.sock")]));
+        assert!(!valid(&[("docker-host", "10.0.0.5:2375")]));
+    }
+}
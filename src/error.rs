@@ -0,0 +1,49 @@
+//! Errors that stop the exporter, logged by `main` before it exits with
+//! [`Error::exit_code`]
+
+/// Why the exporter couldn't start or run to the end
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    /// Invalid command line or config file, shown together with the usage
+    #[error("{0}")]
+    Config(String),
+    /// The `--backend cli` binary can't be run
+    #[error("{}", no_binary(.binary, .path))]
+    Spawn { binary: String, path: String },
+    /// The Prometheus endpoint can't be served on `addr`
+    #[error("Cannot listen on {addr}: {reason}")]
+    Bind { addr: String, reason: String },
+    /// The InfluxDB client can't be set up
+    #[error("{0}")]
+    Influx(String),
+    /// Any other target can't be set up or reached, `target` is its `--target` name
+    #[error("Cannot set up --target {target}: {reason}")]
+    Target { target: &'static str, reason: String },
+    /// The `--record` file can't be created
+    #[error("Cannot create --record file {path}: {source}")]
+    Record { path: String, source: std::io::Error },
+    /// `--check` found a problem, the checks print the details
+    #[error("Checks failed")]
+    Check,
+    /// `--once` couldn't collect a snapshot from every docker host
+    #[error("Stats could not be collected from every docker host")]
+    Collection,
+}
+impl Error {
+    /// 2 for invalid options, 3 if the docker CLI is missing, 1 otherwise
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Spawn { .. } => 3,
+            _ => 1,
+        }
+    }
+}
+
+fn no_binary(binary: &str, path: &str) -> String {
+    match binary {
+        "docker" => format!("The docker binary was not found in PATH ({}). Install the docker CLI or use --backend api to talk to the daemon directly.", path),
+        "ssh" => format!("The ssh binary for --ssh was not found in PATH ({})", path),
+        binary => format!("The --runtime-binary {} was not found (PATH: {})", binary, path),
+    }
+}
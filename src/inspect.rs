@@ -0,0 +1,634 @@
+//! Container details that `docker stats` doesn't report, looked up with
+//! `docker inspect` (or `GET /containers/{id}/json` with the API backend)
+//! and cached per container.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+use crate::{CollectorStats, CpuMode, DockerTarget, HEALTH_STATUSES, Sample};
+use crate::api::Endpoint;
+
+/// How often cached details are refreshed when streaming (no `--interval`)
+const DEFAULT_REFRESH: Duration = Duration::from_secs(60);
+
+/// Ids in stats output shorter than this are not matched as a prefix of the
+/// full id, they may as well be a name filled in for a missing `{{.ID}}`
+const MIN_ID_PREFIX: usize = 12;
+
+/// The parts of `docker inspect` output we use
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+pub(crate) struct ContainerInfo {
+    id: String,
+    /// With a leading `/`
+    name: String,
+    /// Restarts by the restart policy, reset when the container is started by hand
+    restart_count: u64,
+    state: ContainerState,
+    config: ContainerConfig,
+    host_config: Option<HostConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct ContainerState {
+    started_at: String,
+    /// The last run was ended by the kernel's OOM killer, reset on the next start
+    #[serde(rename = "OOMKilled")]
+    oom_killed: bool,
+    /// Only there for containers with a HEALTHCHECK
+    health: Option<ContainerHealth>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct ContainerHealth {
+    /// `starting`, `healthy` or `unhealthy`
+    status: String,
+    /// Failed checks in a row
+    failing_streak: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct ContainerConfig {
+    image: String,
+    /// `null` for containers without labels
+    labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "PascalCase")]
+struct HostConfig {
+    /// Memory limit in bytes, 0 if unlimited
+    memory: u64,
+    /// CPU limit in billionths of a CPU (`--cpus`), 0 if unlimited
+    nano_cpus: u64,
+    /// `host`, `bridge`, `none`, `default`, `container:<id>` or a network name
+    network_mode: String,
+}
+
+/// The part of `docker info` (`GET /info`) we use
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DaemonInfo {
+    #[serde(rename = "NCPU")]
+    ncpu: u64,
+}
+
+impl ContainerInfo {
+    /// Whether this is the container stats output calls `id`: its full id,
+    /// a prefix of it of at least [`MIN_ID_PREFIX`] characters, or its name
+    fn is(&self, id: &str) -> bool {
+        self.id == id
+            || (id.len() >= MIN_ID_PREFIX && self.id.starts_with(id))
+            || self.name.trim_start_matches('/') == id
+    }
+
+    /// Start time of the current run, `None` if it never started
+    fn started_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.state.started_at).ok()
+            .map(|t| t.with_timezone(&Utc))
+            // Docker reports `0001-01-01T00:00:00Z` for containers that were never started
+            .filter(|t| t.timestamp() > 0)
+    }
+
+    /// The network mode as a label value, `container` for one that shares
+    /// the namespace of another container
+    fn network_mode(&self) -> String {
+        match self.host_config.as_ref().map_or("", |host| host.network_mode.as_str()) {
+            // What `docker run` without `--network` gets on Linux
+            "default" => "bridge".to_string(),
+            mode if mode.starts_with("container:") => "container".to_string(),
+            mode => mode.to_string(),
+        }
+    }
+
+    fn label(&self, key: &str) -> String {
+        self.config.labels.as_ref().and_then(|labels| labels.get(key)).cloned().unwrap_or_default()
+    }
+}
+
+/// Where container details are looked up
+#[derive(Clone)]
+pub(crate) enum Source {
+    Cli(DockerTarget),
+    Api(Endpoint),
+}
+
+/// How long samples held back for a lookup are waited for on shutdown
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What an [`Inspector`] and its lookup task share
+struct Shared {
+    /// By short id, `None` if the container could not be inspected
+    cache: HashMap<String, Option<ContainerInfo>>,
+    /// Containers with a sample (or a first lookup) since the last refresh
+    seen: HashSet<String>,
+    /// Last enriched sample per short id, until the container went away
+    last: HashMap<String, Sample>,
+    /// Last samples of containers that went away after an OOM kill, not yet taken
+    departed: Vec<Sample>,
+    /// For the containers without a CPU limit
+    host_cpus: f64,
+}
+
+/// Adds cached container details to samples. The lookups run in a task of
+/// their own, so a slow `docker inspect` never holds up the pipeline: the
+/// details of a new container are fetched when its first sample shows up,
+/// which is held back until they are there, and all containers seen since
+/// the last refresh are re-inspected together once per refresh period.
+///
+/// Containers that stopped reporting are inspected once more on the next
+/// refresh; if the OOM killer ended them, their last sample is kept for
+/// [`Inspector::oom_killed`].
+///
+/// With [`CpuMode::Normalized`] the CPU usage, already capped by [`CpuClamp`],
+/// is divided by the container's CPUs.
+pub(crate) struct Inspector {
+    cpu_mode: CpuMode,
+    /// `--label-map` (docker label, metric label) pairs
+    label_map: Vec<(String, String)>,
+    shared: Arc<Mutex<Shared>>,
+    /// Short ids for the task to look up
+    lookups: mpsc::UnboundedSender<String>,
+    /// Latest sample per short id of the containers waiting for their lookup
+    held: HashMap<String, Sample>,
+}
+
+impl Inspector {
+    /// Start the lookup task, refreshing every `interval` (every minute when
+    /// streaming). It sends `host` to `ready` whenever held samples may be ready.
+    fn spawn(
+        source: Source,
+        interval: Option<Duration>,
+        cpu_mode: CpuMode,
+        label_map: Vec<(String, String)>,
+        host: String,
+        ready: mpsc::UnboundedSender<String>,
+    ) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            cache: HashMap::new(),
+            seen: HashSet::new(),
+            last: HashMap::new(),
+            departed: Vec::new(),
+            host_cpus: local_cpus(),
+        }));
+        let (lookups, requests) = mpsc::unbounded_channel();
+        let refresh = interval.unwrap_or(DEFAULT_REFRESH);
+        tokio::spawn(look_up(source, refresh, Arc::clone(&shared), requests, host, ready));
+        Inspector { cpu_mode, label_map, shared, lookups, held: HashMap::new() }
+    }
+
+    /// `sample` with the inspect based fields filled in, `None` if the
+    /// details of its container are still being looked up
+    fn enrich(&mut self, sample: Sample) -> Option<Sample> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.cache.contains_key(&sample.id) {
+            return Some(self.complete(&mut shared, sample));
+        }
+        let id = sample.id.clone();
+        if self.held.insert(id.clone(), sample).is_none() {
+            // Fails only once the task is gone, `release` lets the sample through then
+            let _ = self.lookups.send(id);
+        }
+        None
+    }
+
+    /// The held samples whose details have been looked up in the meantime
+    fn ready(&mut self) -> Vec<Sample> {
+        let mut shared = self.shared.lock().unwrap();
+        let ids: Vec<String> = self.held.keys().filter(|id| shared.cache.contains_key(*id)).cloned().collect();
+        let ready: Vec<Sample> = ids.iter().filter_map(|id| self.held.remove(id)).collect();
+        ready.into_iter().map(|sample| self.complete(&mut shared, sample)).collect()
+    }
+
+    /// All held samples, those still without details as they are
+    fn release(&mut self) -> Vec<Sample> {
+        let mut shared = self.shared.lock().unwrap();
+        let held = std::mem::take(&mut self.held);
+        held.into_values().map(|sample| self.complete(&mut shared, sample)).collect()
+    }
+
+    /// Fill in the details of `sample` from the cache, if any
+    fn complete(&self, shared: &mut Shared, mut sample: Sample) -> Sample {
+        if let Some(Some(info)) = shared.cache.get(&sample.id) {
+            sample.started_at = info.started_at();
+            sample.image = info.config.image.clone();
+            sample.restart_count = Some(info.restart_count);
+            sample.oom_killed = Some(info.state.oom_killed);
+            // Docker leaves the status empty (or says `none`) without a healthcheck
+            if let Some(health) = info.state.health.as_ref().filter(|health| HEALTH_STATUSES.contains(&health.status.as_str())) {
+                sample.health_status = Some(health.status.clone());
+                sample.health_failing_streak = Some(health.failing_streak);
+            }
+            sample.compose_project = info.label("com.docker.compose.project");
+            sample.compose_service = info.label("com.docker.compose.service");
+            sample.network_mode = info.network_mode();
+            sample.mapped_labels = self.label_map.iter()
+                .map(|(docker_label, label)| (label.clone(), info.label(docker_label)))
+                .collect();
+            // Without a limit docker stats reports the host's memory instead
+            if info.host_config.as_ref().is_some_and(|host| host.memory == 0) {
+                sample.mem_limit = None;
+            }
+            sample.cpu_cores = match info.host_config.as_ref().map_or(0, |host| host.nano_cpus) {
+                0 => Some(shared.host_cpus),
+                nano_cpus => Some(nano_cpus as f64 / 1e9),
+            };
+        }
+        if self.cpu_mode == CpuMode::Normalized
+            && let Some(cores) = sample.cpu_cores
+        {
+            sample.cpu /= cores;
+        }
+        shared.seen.insert(sample.id.clone());
+        shared.last.insert(sample.id.clone(), sample.clone());
+        sample
+    }
+
+    /// The last samples of containers that went away after an OOM kill
+    /// since the previous call, to be recorded once more. The Prometheus
+    /// based sinks keep their series for a while (`OOM_GRACE` there).
+    fn oom_killed(&mut self) -> Vec<Sample> {
+        std::mem::take(&mut self.shared.lock().unwrap().departed)
+    }
+}
+
+/// The [`Inspector`]s of all docker hosts, by [`Sample::host`]
+pub(crate) struct Inspectors {
+    by_host: HashMap<String, Inspector>,
+    ready_tx: mpsc::UnboundedSender<String>,
+    /// Hosts whose lookup task has new details
+    ready_rx: mpsc::UnboundedReceiver<String>,
+}
+
+impl Inspectors {
+    pub(crate) fn new() -> Self {
+        let (ready_tx, ready_rx) = mpsc::unbounded_channel();
+        Inspectors { by_host: HashMap::new(), ready_tx, ready_rx }
+    }
+
+    /// Inspect the containers of `host` at `source`, see [`Inspector::spawn`]
+    pub(crate) fn add_host(&mut self, host: String, source: Source, interval: Option<Duration>, cpu_mode: CpuMode, label_map: Vec<(String, String)>) {
+        let inspector = Inspector::spawn(source, interval, cpu_mode, label_map, host.clone(), self.ready_tx.clone());
+        self.by_host.insert(host, inspector);
+    }
+
+    /// See [`Inspector::enrich`], samples of hosts without an inspector pass as they are
+    pub(crate) fn enrich(&mut self, sample: Sample) -> Option<Sample> {
+        match self.by_host.get_mut(&sample.host) {
+            Some(inspector) => inspector.enrich(sample),
+            None => Some(sample),
+        }
+    }
+
+    /// Wait for held samples to become ready. Cancel safe, for `select!`.
+    pub(crate) async fn ready(&mut self) -> Vec<Sample> {
+        // Never `None`, the sender is kept in `self`
+        let host = self.ready_rx.recv().await.unwrap_or_default();
+        self.by_host.get_mut(&host).map(Inspector::ready).unwrap_or_default()
+    }
+
+    /// Wait up to [`SETTLE_TIMEOUT`] for the held samples when the pipeline
+    /// stops, e.g. the only ones of `--once`, then let the rest through as well
+    pub(crate) async fn settle(&mut self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let deadline = tokio::time::Instant::now() + SETTLE_TIMEOUT;
+        while self.by_host.values().any(|inspector| !inspector.held.is_empty()) {
+            match tokio::time::timeout_at(deadline, self.ready()).await {
+                Ok(ready) => samples.extend(ready),
+                Err(_) => {
+                    warn!("Container details still not looked up after {:?}, exporting without", SETTLE_TIMEOUT);
+                    break;
+                }
+            }
+        }
+        samples.extend(self.by_host.values_mut().flat_map(Inspector::release));
+        samples
+    }
+
+    /// [`Inspector::oom_killed`] of all hosts
+    pub(crate) fn oom_killed(&mut self) -> Vec<Sample> {
+        self.by_host.values_mut().flat_map(Inspector::oom_killed).collect()
+    }
+}
+
+/// The lookup task of an [`Inspector`]: inspects the containers sent to
+/// `requests` and refreshes the cache every `refresh`, until the inspector is dropped
+async fn look_up(
+    source: Source,
+    refresh: Duration,
+    shared: Arc<Mutex<Shared>>,
+    mut requests: mpsc::UnboundedReceiver<String>,
+    host: String,
+    ready: mpsc::UnboundedSender<String>,
+) {
+    let host_cpus = HostCpus::new(source.clone()).get().await;
+    shared.lock().unwrap().host_cpus = host_cpus;
+    let mut refresh_tick = tokio::time::interval_at(tokio::time::Instant::now() + refresh, refresh);
+    refresh_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            id = requests.recv() => {
+                let Some(id) = id else { break };
+                // Containers that showed up in the meantime go along in the same call
+                let mut ids = vec![id];
+                while let Ok(id) = requests.try_recv() {
+                    ids.push(id);
+                }
+                let infos = inspect(&source, &ids).await;
+                {
+                    let mut shared = shared.lock().unwrap();
+                    shared.cache.extend(infos);
+                    shared.seen.extend(ids);
+                }
+                let _ = ready.send(host.clone());
+            }
+            _ = refresh_tick.tick() => refresh_cache(&source, &shared).await,
+        }
+    }
+}
+
+/// Re-inspect the containers seen since the last refresh, forget the others
+/// and check why they went away. Runs on the timer of the lookup task, so a
+/// container that went away is checked even if no other one reports.
+async fn refresh_cache(source: &Source, shared: &Mutex<Shared>) {
+    let ids: Vec<String> = shared.lock().unwrap().seen.drain().collect();
+    let infos = inspect(source, &ids).await;
+    let refreshed: HashSet<&String> = ids.iter().collect();
+    let gone: Vec<String> = {
+        let mut shared = shared.lock().unwrap();
+        let Shared { cache, seen, last, .. } = &mut *shared;
+        // Keeps the containers looked up for the first time during the refresh
+        cache.retain(|id, _| seen.contains(id));
+        cache.extend(infos);
+        last.keys().filter(|id| !refreshed.contains(id)).cloned().collect()
+    };
+    if !gone.is_empty() {
+        check_departed(source, shared, &gone).await;
+    }
+}
+
+/// Keep the last sample of the `gone` containers that were OOM-killed,
+/// as stopped, forget the others
+async fn check_departed(source: &Source, shared: &Mutex<Shared>, gone: &[String]) {
+    let infos = inspect(source, gone).await;
+    let mut shared = shared.lock().unwrap();
+    for (id, info) in infos {
+        let last = shared.last.remove(&id);
+        // `None` if the container was removed in the meantime
+        if let (Some(info), Some(mut last)) = (info, last)
+            && info.state.oom_killed
+        {
+            warn!("Container {} ({}) was killed for running out of memory", last.name, id);
+            last.stopped = true;
+            last.oom_killed = Some(true);
+            shared.departed.push(last);
+        }
+    }
+}
+
+/// Look up `ids`, containers that vanished in the meantime map to `None`
+async fn inspect(source: &Source, ids: &[String]) -> HashMap<String, Option<ContainerInfo>> {
+    let infos = match source {
+        Source::Cli(docker) => inspect_cli(docker, ids).await,
+        Source::Api(endpoint) => {
+            let requests = ids.iter().map(|id| async move {
+                endpoint.get_json::<ContainerInfo>(&format!("/containers/{}/json", id)).await
+            });
+            futures::future::join_all(requests).await.into_iter()
+                .filter_map(|info| info.map_err(|e| debug!("Container inspect failed: {}", e)).ok())
+                .collect()
+        }
+    };
+    ids.iter()
+        .map(|id| (id.clone(), infos.iter().find(|info| info.is(id)).cloned()))
+        .collect()
+}
+
+/// CPUs of a docker host from `docker info`, or of this machine if that
+/// fails, looked up on first use
+pub(crate) struct HostCpus {
+    source: Source,
+    cpus: Option<f64>,
+}
+
+impl HostCpus {
+    pub(crate) fn new(source: Source) -> Self {
+        HostCpus { source, cpus: None }
+    }
+
+    async fn get(&mut self) -> f64 {
+        if let Some(cpus) = self.cpus {
+            return cpus;
+        }
+        let info = match &self.source {
+            Source::Cli(docker) => docker_info_cli(docker).await,
+            Source::Api(endpoint) => endpoint.get_json::<DaemonInfo>("/info").await
+                .map_err(|e| warn!("Docker info request failed: {}", e))
+                .ok(),
+        };
+        let cpus = match info {
+            Some(info) if info.ncpu > 0 => info.ncpu as f64,
+            _ => local_cpus(),
+        };
+        debug!("Docker host has {} CPUs", cpus);
+        self.cpus = Some(cpus);
+        cpus
+    }
+}
+
+fn local_cpus() -> f64 {
+    std::thread::available_parallelism().map_or(1, |n| n.get()) as f64
+}
+
+/// Caps CPU readings above `--cpu-max-clamp` percent (default 100 per CPU of
+/// the docker host) and counts them, docker sometimes reports absurd values
+/// right after a container started. Samples of hosts without a daemon to ask,
+/// as read with `--stats-source`, are capped at 100 per CPU of this machine.
+pub(crate) struct CpuClamp {
+    max: Option<f64>,
+    /// By [`Sample::host`]
+    hosts: HashMap<String, HostCpus>,
+    stats: CollectorStats,
+}
+
+impl CpuClamp {
+    pub(crate) fn new(max: Option<f64>, stats: CollectorStats) -> Self {
+        CpuClamp { max, hosts: HashMap::new(), stats }
+    }
+
+    /// Look up the default maximum of the samples of `host` at `source`
+    pub(crate) fn add_host(&mut self, host: String, source: Source) {
+        self.hosts.insert(host, HostCpus::new(source));
+    }
+
+    pub(crate) async fn apply(&mut self, sample: &mut Sample) {
+        let max_cpu = match (self.max, self.hosts.get_mut(&sample.host)) {
+            (Some(max), _) => max,
+            (None, Some(host)) => 100.0 * host.get().await,
+            (None, None) => 100.0 * local_cpus(),
+        };
+        if sample.cpu > max_cpu {
+            debug!("Capping CPU reading of {}: {}%", sample.name, sample.cpu);
+            self.stats.cpu_clamped.inc();
+            sample.cpu = max_cpu;
+        }
+    }
+}
+
+/// Run `docker inspect` for all `ids` at once. It still prints the containers
+/// it found (and fails) if some of them are gone, so the status is ignored.
+async fn inspect_cli(docker: &DockerTarget, ids: &[String]) -> Vec<ContainerInfo> {
+    let mut args = vec!["inspect", "--type", "container"];
+    args.extend(ids.iter().map(String::as_str));
+    let output = docker.command(&args)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await;
+    match output {
+        Ok(output) => serde_json::from_slice(&output.stdout).unwrap_or_else(|e| {
+            if !output.stdout.is_empty() {
+                warn!("Unparseable docker inspect output: {}", e);
+            }
+            Vec::new()
+        }),
+        Err(e) => {
+            warn!("Failed to run docker inspect: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+async fn docker_info_cli(docker: &DockerTarget) -> Option<DaemonInfo> {
+    let output = docker.command(&["info", "--format", "{{json .}}"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| warn!("Failed to run docker info: {}", e))
+        .ok()?;
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| warn!("Unparseable docker info output: {}", e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// `docker inspect` output of a running container and one the OOM killer ended
+    const FIXTURE: &str = r#"[
+        {"Id":"abc123def456aaaa","Name":"/web","RestartCount":2,
+         "State":{"StartedAt":"2026-10-14T00:00:00.5Z","OOMKilled":false},
+         "Config":{"Image":"nginx:latest","Labels":{"com.docker.compose.project":"shop","team":"payments"}},
+         "HostConfig":{"Memory":0,"NanoCpus":2000000000,"NetworkMode":"default"}},
+        {"Id":"fed654cba321bbbb","Name":"/worker","RestartCount":0,
+         "State":{"StartedAt":"2026-10-14T00:00:00Z","OOMKilled":true},
+         "Config":{"Image":"worker:1","Labels":null},
+         "HostConfig":{"Memory":268435456,"NanoCpus":0,"NetworkMode":"container:abc123def456"}}
+    ]"#;
+
+    /// A docker CLI that answers every command with [`FIXTURE`]
+    fn fixture_cli(name: &str) -> Source {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("dme-inspect-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("inspect.json");
+        std::fs::write(&json, FIXTURE).unwrap();
+        let binary: PathBuf = dir.join("docker");
+        std::fs::write(&binary, format!("#!/bin/sh\ncat '{}'\n", json.display())).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        Source::Cli(DockerTarget { binary: binary.display().to_string(), ..DockerTarget::default() })
+    }
+
+    fn shared() -> Mutex<Shared> {
+        Mutex::new(Shared { cache: HashMap::new(), seen: HashSet::new(), last: HashMap::new(), departed: Vec::new(), host_cpus: 8.0 })
+    }
+
+    fn sample(name: &str, id: &str) -> Sample {
+        Sample { name: name.to_string(), id: id.to_string(), cpu: 100.0, mem_limit: Some(1 << 30), ..Sample::default() }
+    }
+
+    #[test]
+    fn details_come_from_the_cache() {
+        let infos: Vec<ContainerInfo> = serde_json::from_str(FIXTURE).unwrap();
+        let state = shared();
+        let mut locked = state.lock().unwrap();
+        locked.cache.insert("abc123def456".to_string(), Some(infos[0].clone()));
+        locked.cache.insert("fed654cba321".to_string(), Some(infos[1].clone()));
+        let inspector = Inspector {
+            cpu_mode: CpuMode::Normalized,
+            label_map: vec![("team".to_string(), "team".to_string())],
+            shared: Arc::new(shared()),
+            lookups: mpsc::unbounded_channel().0,
+            held: HashMap::new(),
+        };
+
+        let web = inspector.complete(&mut locked, sample("web", "abc123def456"));
+        assert_eq!(web.image, "nginx:latest");
+        assert_eq!(web.restart_count, Some(2));
+        assert_eq!(web.oom_killed, Some(false));
+        assert_eq!(web.compose_project, "shop");
+        assert_eq!(web.network_mode, "bridge");
+        assert_eq!(web.mapped_labels.get("team").map(String::as_str), Some("payments"));
+        // No memory limit, so the one docker stats reported is the host's
+        assert_eq!(web.mem_limit, None);
+        assert_eq!((web.cpu_cores, web.cpu), (Some(2.0), 50.0));
+        assert!(web.started_at.is_some());
+
+        let worker = inspector.complete(&mut locked, sample("worker", "fed654cba321"));
+        assert_eq!(worker.oom_killed, Some(true));
+        assert_eq!(worker.network_mode, "container");
+        assert_eq!(worker.mem_limit, Some(1 << 30));
+        assert_eq!(worker.cpu_cores, Some(8.0));
+        assert!(locked.seen.contains("fed654cba321") && locked.last.contains_key("fed654cba321"));
+    }
+
+    #[tokio::test]
+    async fn departed_containers_are_kept_only_after_an_oom_kill() {
+        let source = fixture_cli("departed");
+        let shared = shared();
+        {
+            let mut shared = shared.lock().unwrap();
+            shared.last.insert("abc123def456".to_string(), sample("web", "abc123def456"));
+            shared.last.insert("fed654cba321".to_string(), sample("worker", "fed654cba321"));
+        }
+        check_departed(&source, &shared, &["abc123def456".to_string(), "fed654cba321".to_string()]).await;
+        let shared = shared.lock().unwrap();
+        assert!(shared.last.is_empty());
+        let [worker] = shared.departed.as_slice() else { panic!("expected one departed sample, got {:?}", shared.departed) };
+        assert_eq!(worker.name, "worker");
+        assert!(worker.stopped);
+        assert_eq!(worker.oom_killed, Some(true));
+    }
+
+    #[tokio::test]
+    async fn a_refresh_checks_the_containers_that_stopped_reporting() {
+        let source = fixture_cli("refresh");
+        let shared = shared();
+        {
+            let mut shared = shared.lock().unwrap();
+            shared.last.insert("abc123def456".to_string(), sample("web", "abc123def456"));
+            shared.last.insert("fed654cba321".to_string(), sample("worker", "fed654cba321"));
+            shared.cache.insert("fed654cba321".to_string(), None);
+            // Only web reported since the last refresh
+            shared.seen.insert("abc123def456".to_string());
+        }
+        refresh_cache(&source, &shared).await;
+        let shared = shared.lock().unwrap();
+        assert!(shared.cache.contains_key("abc123def456") && !shared.cache.contains_key("fed654cba321"));
+        assert!(shared.seen.is_empty());
+        assert!(shared.last.contains_key("abc123def456") && !shared.last.contains_key("fed654cba321"));
+        assert_eq!(shared.departed.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), ["worker"]);
+    }
+}
@@ -1,206 +1,1644 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::net::SocketAddr;
 use std::process::Stdio;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use influxdb::{Client, InfluxDbWriteable, Timestamp};
-use prometheus::{Encoder, GaugeVec, TextEncoder, Registry};
+use chrono::{DateTime, Utc};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, Registry};
+use log::{debug, error, info, warn};
+use regex::Regex;
 use serde::Deserialize;
-use tokio::{io::{AsyncBufReadExt, BufReader}, process::Command, sync::Mutex};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader}, process::Command, sync::{mpsc, oneshot}};
+use futures::FutureExt;
 use warp::Filter;
-use chrono::Utc;
 
-/// Print usage information
-fn usage() {
-    eprintln!("Usage: docker_metrics_exporter [--target prometheus|influxdb] [-p PORT] [--host HOST] [--db DB]");
-    eprintln!("  --target   prometheus (default) or influxdb");
-    eprintln!("  -p, --port   Port for HTTP (Prometheus) or InfluxDB server");
-    eprintln!("  --host       InfluxDB host (default: localhost)");
-    eprintln!("  --db         InfluxDB database (default: metrics)");
-    eprintln!("  -h, --help   Show this help");
-}
+use config::{Bind, Config, usage};
+use error::Error;
+use inspect::{CpuClamp, Inspectors};
+#[cfg(feature = "kafka")]
+use sink::KafkaSink;
+#[cfg(feature = "remote-write")]
+use sink::RemoteWriteSink;
+use sink::{AnySink, Fanout, GraphiteSink, InfluxApi, InfluxSink, JsonlSink, Metrics, OtlpSink, PrometheusSink, PushgatewaySink, Sink, StatsdSink, TopSink};
+
+mod api;
+mod check;
+mod config;
+mod error;
+mod inspect;
+mod sink;
+mod stopped;
 
-#[derive(Debug, Deserialize)]
+/// A line of `docker stats` output. Every key may be left out by a custom
+/// `--stats-format`, but at least one of `ID` and `Name` is needed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
 struct DockerStat {
+    /// Short container id as reported by `docker stats`
+    #[serde(rename = "ID")]
+    id: Option<String>,
     #[serde(rename = "Name")]
-    name: String,
+    name: Option<String>,
     #[serde(rename = "CPUPerc")]
-    cpu_perc: String,
+    cpu_perc: Option<String>,
     #[serde(rename = "MemUsage")]
-    mem_usage: String,
+    mem_usage: Option<String>,
+    #[serde(rename = "MemPerc")]
+    mem_perc: Option<String>,
     #[serde(rename = "NetIO")]
-    net_io: String,
+    net_io: Option<String>,
     #[serde(rename = "BlockIO")]
-    block_io: String,
+    block_io: Option<String>,
+    #[serde(rename = "PIDs")]
+    pids: Option<String>,
+}
+
+/// Keys [`DockerStat`] reads from every `docker stats` line
+const DOCKER_STAT_KEYS: [&str; 8] = ["ID", "Name", "CPUPerc", "MemUsage", "MemPerc", "NetIO", "BlockIO", "PIDs"];
+
+/// `docker stats --format` template with all of [`DOCKER_STAT_KEYS`]
+const DEFAULT_STATS_FORMAT: &str = "{{json .}}";
+
+/// How `docker stats` output is requested and read
+#[derive(Clone, Debug)]
+struct StatsFormat {
+    /// Go template for `docker stats --format`, printing one JSON object per container
+    template: String,
+    locale: Locale,
+    /// Print every line to stderr as read, `--raw-stats`
+    raw: bool,
+    /// Give up on output that stays silent this long, `--watchdog-timeout`
+    watchdog: Option<Duration>,
+}
+
+/// Keys current docker versions print that are deliberately not used
+const IGNORED_DOCKER_STAT_KEYS: [&str; 1] = ["Container"];
+
+/// Check a `docker stats` line against [`DockerStat`], expecting all of
+/// [`DOCKER_STAT_KEYS`] if `all_keys` (the default template). On a mismatch
+/// the line and the missing and unexpected keys are logged as errors, which
+/// helps to spot a changed docker output format.
+fn check_format(line: &str, all_keys: bool) -> bool {
+    let err = match serde_json::from_str::<DockerStat>(line) {
+        Ok(stat) if stat.id.is_some() || stat.name.is_some() => {
+            let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line).unwrap_or_default();
+            if !all_keys || DOCKER_STAT_KEYS.iter().all(|key| fields.contains_key(*key)) {
+                return true;
+            }
+            "not all keys of the default format".to_string()
+        }
+        Ok(_) => "neither ID nor Name".to_string(),
+        Err(e) => e.to_string(),
+    };
+    match serde_json::from_str::<serde_json::Value>(line) {
+        Ok(serde_json::Value::Object(fields)) => {
+            let missing: Vec<&str> = DOCKER_STAT_KEYS.into_iter().filter(|key| !fields.contains_key(*key)).collect();
+            let unexpected: Vec<&str> = fields.keys()
+                .map(String::as_str)
+                .filter(|key| !DOCKER_STAT_KEYS.contains(key) && !IGNORED_DOCKER_STAT_KEYS.contains(key))
+                .collect();
+            error!("docker stats output doesn't match the expected format ({}): missing keys [{}], unexpected keys [{}]",
+                err, missing.join(", "), unexpected.join(", "));
+        }
+        _ => error!("docker stats output is not a JSON object ({})", err),
+    }
+    error!("docker stats line: {}", line);
+    false
+}
+
+/// Remove the terminal control sequences `docker stats` may put in front of
+/// a line when streaming, e.g. the `ESC[2J ESC[H` that clears the screen
+/// before every refresh, and a trailing carriage return
+fn strip_terminal_noise(line: &str) -> &str {
+    let mut line = line.trim_end_matches('\r');
+    while let Some(rest) = line.strip_prefix("\x1b[") {
+        // Parameter bytes, then a single final byte in `@`..=`~`
+        let params = rest.find(|c: char| !(c.is_ascii_digit() || c == ';' || c == '?')).unwrap_or(rest.len());
+        match rest[params..].chars().next() {
+            Some(c) if ('@'..='~').contains(&c) => line = &rest[params + 1..],
+            _ => break,
+        }
+    }
+    line
+}
+
+/// The column header of the table format, printed by docker versions that ignore `--format`
+fn is_header(line: &str) -> bool {
+    line.starts_with("CONTAINER") || line.starts_with("NAME ")
+}
+
+/// How `docker_cpu_percent` is reported
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum CpuMode {
+    /// As shown by `docker stats`, 100% per fully used core
+    #[default]
+    Raw,
+    /// Divided by [`Sample::cpu_cores`], 100% when all allocated cores are busy
+    Normalized,
+}
+impl std::str::FromStr for CpuMode {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "raw" => Ok(CpuMode::Raw),
+            "normalized" => Ok(CpuMode::Normalized),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Decimal separator used in `docker stats` output
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Locale {
+    /// Guess from the position and count of separators
+    #[default]
+    Auto,
+    /// `1,234.5`
+    Dot,
+    /// `1.234,5`
+    Comma,
+}
+impl std::str::FromStr for Locale {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "auto" => Ok(Locale::Auto),
+            "dot" => Ok(Locale::Dot),
+            "comma" => Ok(Locale::Comma),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parse a number that may use `.` or `,` as decimal separator and the other
+/// one for grouping thousands (`1.5`, `1,5`, `1,234.5`, `1.234,5`).
+/// With [`Locale::Auto`] the last separator is the decimal one unless it
+/// occurs more than once, so `1,234` is read as 1.234 and `1,234,567` as
+/// 1234567.
+fn parse_decimal(s: &str, locale: Locale) -> Option<f64> {
+    let decimal = match locale {
+        Locale::Dot => '.',
+        Locale::Comma => ',',
+        Locale::Auto => match s.rfind(['.', ',']).map(|i| &s[i..i + 1]) {
+            Some(sep) if s.matches(sep).count() > 1 => if sep == "," { '.' } else { ',' },
+            Some(",") => ',',
+            _ => '.',
+        },
+    };
+    let grouping = if decimal == '.' { ',' } else { '.' };
+    s.trim().replace(grouping, "").replace(decimal, ".").parse().ok()
+}
+
+/// Parse a docker size string like `1.5GiB`, `12.3kB` or `0B` into bytes.
+/// Returns `None` if the number or the unit is not recognized.
+fn parse_bytes(s: &str, locale: Locale) -> Option<u64> {
+    // Binary units are used for memory, SI units for network and block I/O
+    const UNITS: [(&str, f64); 12] = [
+        ("KiB", 1024.0), ("MiB", 1048576.0), ("GiB", 1073741824.0), ("TiB", 1099511627776.0), ("PiB", 1125899906842624.0),
+        ("kB", 1e3), ("KB", 1e3), ("MB", 1e6), ("GB", 1e9), ("TB", 1e12), ("PB", 1e15),
+        ("B", 1.0),
+    ];
+    let s = s.trim();
+    let (num, factor) = UNITS.iter()
+        .find_map(|(unit, factor)| s.strip_suffix(unit).map(|num| (num, *factor)))
+        .unwrap_or((s, 1.0));
+    let num = parse_decimal(num, locale)?;
+    if !num.is_finite() || num < 0.0 {
+        return None;
+    }
+    Some((num * factor).round() as u64)
+}
+fn parse_io(s: &str, locale: Locale) -> (Option<u64>, Option<u64>) {
+    let parts: Vec<&str> = s.split('/').map(|x| x.trim()).collect();
+    let a = parts.first().and_then(|x| parse_bytes(x, locale));
+    let b = parts.get(1).and_then(|x| parse_bytes(x, locale));
+    (a, b)
 }
 
-#[derive(InfluxDbWriteable)]
-struct DockerMetrics {
-    time: Timestamp,
-    #[influxdb(tag)] name: String,
-    cpu_percent: f64,
+/// One stats sample of a container, independent of the collection backend
+#[derive(Clone, Debug, Default)]
+struct Sample {
+    name: String,
+    id: String,
+    cpu: f64,
     mem_usage: u64,
-    mem_limit: u64,
-    net_input: u64,
-    net_output: u64,
+    /// `None` for containers without a memory limit
+    mem_limit: Option<u64>,
+    /// Usage in % of the limit, or of the host's memory without one, as docker reports it
+    mem_percent: Option<f64>,
+    /// Page cache, anonymous memory (RSS) and swap used, from the cgroup
+    /// memory stats, `--backend api` only. Swap is only known on cgroup v1
+    /// with swap accounting enabled.
+    mem_cache: Option<u64>,
+    mem_rss: Option<u64>,
+    mem_swap: Option<u64>,
+    net_in: u64,
+    net_out: u64,
     block_read: u64,
     block_write: u64,
+    pids: u64,
+    /// CFS periods in which the container was throttled and the time it was
+    /// held back for, since it started, `--backend api` only
+    cpu_throttled_periods: Option<u64>,
+    cpu_throttled_seconds: Option<f64>,
+    /// Received and sent bytes per network interface, `--backend api` only
+    net_interfaces: BTreeMap<String, (u64, u64)>,
+    /// Read and written bytes per block device (`major:minor`), `--backend api` only
+    block_devices: BTreeMap<String, (u64, u64)>,
+    /// The container exists but isn't running, all metrics are 0 (`--include-stopped`)
+    stopped: bool,
+    /// Docker host the sample was collected from, empty for the default one of the CLI
+    host: String,
+    /// When the stats were read, `None` for the placeholders of stopped containers
+    read_at: Option<DateTime<Utc>>,
+    // From `docker inspect`, see [`inspect::Inspector`]
+    /// CPUs the container may use: its `--cpus` limit or all CPUs of the host
+    cpu_cores: Option<f64>,
+    started_at: Option<DateTime<Utc>>,
+    restart_count: Option<u64>,
+    /// The OOM killer ended the last run
+    oom_killed: Option<bool>,
+    /// One of [`HEALTH_STATUSES`] and the failed checks in a row, `None` without a HEALTHCHECK
+    health_status: Option<String>,
+    health_failing_streak: Option<u64>,
+    image: String,
+    compose_project: String,
+    compose_service: String,
+    /// `host`, `bridge`, `none`, `container` or a network name, empty if not inspected
+    network_mode: String,
+    /// Values of the `--label-map` docker labels by metric label, empty if not inspected
+    mapped_labels: BTreeMap<String, String>,
 }
 
-fn parse_bytes(s: &str) -> u64 {
-    let units = [("GiB", 1024_u64.pow(3)), ("MiB", 1024_u64.pow(2)), ("kB", 1024), ("B", 1)];
-    for (unit, factor) in units {
-        if s.ends_with(unit) {
-            let num = s[..s.len() - unit.len()].trim().replace(',', ".").parse::<f64>().unwrap_or(0.0);
-            return (num * factor as f64) as u64;
+/// Labels that can be added to every series with `--labels`
+const EXTRA_LABELS: [&str; 5] = ["host", "image", "compose_project", "compose_service", "network_mode"];
+
+/// Metrics that can be turned off with `--disable-metric`, by InfluxDB field name
+const METRIC_NAMES: [&str; 20] = [
+    "cpu_percent", "cpu_cores", "mem_usage", "mem_limit", "mem_limited", "mem_percent", "mem_cache", "mem_rss",
+    "mem_swap", "net_input", "net_output", "block_read", "block_write", "pids", "uptime_seconds", "running",
+    "restart_count", "oom_killed", "cpu_throttled", "health",
+];
+
+/// Metrics that need `docker inspect`; `mem_limit` and `mem_limited` to
+/// recognize the host's memory reported as the limit of unlimited containers
+const INSPECT_METRICS: [&str; 7] = ["cpu_cores", "mem_limit", "mem_limited", "uptime_seconds", "restart_count", "oom_killed", "health"];
+
+/// The states of a container's HEALTHCHECK as docker reports them
+const HEALTH_STATUSES: [&str; 3] = ["healthy", "unhealthy", "starting"];
+
+impl Sample {
+    /// Seconds since the container was started, if known
+    fn uptime(&self) -> Option<f64> {
+        self.started_at
+            .filter(|_| !self.stopped)
+            .map(|started| (Utc::now() - started).num_milliseconds() as f64 / 1000.0)
+            .filter(|&secs| secs >= 0.0)
+    }
+
+    /// Value of one of the [`EXTRA_LABELS`] or a `--label-map` label, empty if unknown
+    fn extra_label(&self, label: &str) -> &str {
+        match label {
+            "host" => &self.host,
+            "image" => &self.image,
+            "compose_project" => &self.compose_project,
+            "compose_service" => &self.compose_service,
+            "network_mode" => &self.network_mode,
+            label => self.mapped_labels.get(label).map_or("", String::as_str),
         }
     }
-    0
 }
-fn parse_io(s: &str) -> (u64, u64) {
-    let parts: Vec<&str> = s.split('/').map(|x| x.trim()).collect();
-    let a = parts.get(0).map(|x| parse_bytes(x)).unwrap_or(0);
-    let b = parts.get(1).map(|x| parse_bytes(x)).unwrap_or(0);
-    (a, b)
+
+/// Whether docker has no readings for the container yet, as right after it
+/// started: every reading in the line (of those the template asks for) is
+/// `--` or `-- / --`. Parsed, it would be a dip to 0 in every graph.
+fn warming_up(stat: &DockerStat) -> bool {
+    let readings = [&stat.cpu_perc, &stat.mem_usage, &stat.mem_perc, &stat.net_io, &stat.block_io];
+    let placeholder = |value: &str| value.split('/').all(|part| part.trim() == "--");
+    readings.iter().any(|value| value.is_some())
+        && readings.iter().all(|value| value.as_deref().is_none_or(placeholder))
 }
 
-/// Parse DockerStat into all metric values
-fn parse_stat(stat: &DockerStat) -> (f64, u64, u64, u64, u64, u64, u64) {
-    let cpu = stat.cpu_perc.trim_end_matches('%').replace(",", ".").parse::<f64>().unwrap_or(0.0);
-    let mem_parts: Vec<&str> = stat.mem_usage.split('/').map(|x| x.trim()).collect();
-    let mem_usage = parse_bytes(mem_parts.get(0).unwrap_or(&"0"));
-    let mem_limit = parse_bytes(mem_parts.get(1).unwrap_or(&"0"));
-    let (net_in, net_out) = parse_io(&stat.net_io);
-    let (blk_read, blk_write) = parse_io(&stat.block_io);
-    (cpu, mem_usage, mem_limit, net_in, net_out, blk_read, blk_write)
-}
-
-struct Metrics {
-    cpu: GaugeVec,
-    mem_usage: GaugeVec,
-    mem_limit: GaugeVec,
-    net_in: GaugeVec,
-    net_out: GaugeVec,
-    block_read: GaugeVec,
-    block_write: GaugeVec,
-}
-impl Metrics {
-    fn new(registry: &Registry) -> Self {
-        let labels = &["name"];
-        let cpu = GaugeVec::new(prometheus::Opts::new("docker_cpu_percent", "CPU usage %"), labels).unwrap();
-        let mem_usage = GaugeVec::new(prometheus::Opts::new("docker_mem_usage_bytes", "Memory used"), labels).unwrap();
-        let mem_limit = GaugeVec::new(prometheus::Opts::new("docker_mem_limit_bytes", "Memory limit"), labels).unwrap();
-        let net_in = GaugeVec::new(prometheus::Opts::new("docker_net_input_bytes", "Network In"), labels).unwrap();
-        let net_out = GaugeVec::new(prometheus::Opts::new("docker_net_output_bytes", "Network Out"), labels).unwrap();
-        let block_read = GaugeVec::new(prometheus::Opts::new("docker_block_read_bytes", "Block I/O Read"), labels).unwrap();
-        let block_write = GaugeVec::new(prometheus::Opts::new("docker_block_write_bytes", "Block I/O Write"), labels).unwrap();
-        for m in [&cpu, &mem_usage, &mem_limit, &net_in, &net_out, &block_read, &block_write] {
-            registry.register(Box::new(m.clone())).unwrap();
-        }
-        Metrics { cpu, mem_usage, mem_limit, net_in, net_out, block_read, block_write }
-    }
-    fn update(&mut self, stat: &DockerStat) {
-        let name = stat.name.as_str();
-        let (cpu, mem_usage, mem_limit, net_in, net_out, blk_read, blk_write) = parse_stat(stat);
-        self.cpu.with_label_values(&[name]).set(cpu);
-        self.mem_usage.with_label_values(&[name]).set(mem_usage as f64);
-        self.mem_limit.with_label_values(&[name]).set(mem_limit as f64);
-        self.net_in.with_label_values(&[name]).set(net_in as f64);
-        self.net_out.with_label_values(&[name]).set(net_out as f64);
-        self.block_read.with_label_values(&[name]).set(blk_read as f64);
-        self.block_write.with_label_values(&[name]).set(blk_write as f64);
+/// Parse DockerStat into all metric values. Left out keys are reported as 0,
+/// a left out `ID` or `Name` is filled in with the other one.
+fn parse_stat(stat: &DockerStat, locale: Locale) -> Sample {
+    let field = |value: &Option<String>| value.as_deref().unwrap_or_default().trim().to_string();
+    let cpu = parse_decimal(field(&stat.cpu_perc).trim_end_matches('%'), locale)
+        .filter(|cpu| cpu.is_finite())
+        .unwrap_or(0.0);
+    let mem = field(&stat.mem_usage);
+    // Older docker versions print only the usage (`50MiB`), which leaves the
+    // limit unknown rather than 0. Unlimited containers show `--`, `N/A` or the
+    // host's memory, depending on the docker version; the latter is
+    // recognized by the inspector.
+    let (mem_usage, mem_limit) = match mem.split_once('/') {
+        Some((usage, limit)) => (usage, parse_bytes(limit.trim(), locale).filter(|&limit| limit > 0)),
+        None => (mem.as_str(), None),
+    };
+    let mem_usage = parse_bytes(mem_usage.trim(), locale).unwrap_or(0);
+    // `--` while docker has no reading yet
+    let mem_percent = parse_decimal(field(&stat.mem_perc).trim_end_matches('%'), locale).filter(|p| p.is_finite());
+    let (net_in, net_out) = parse_io(&field(&stat.net_io), locale);
+    let (blk_read, blk_write) = parse_io(&field(&stat.block_io), locale);
+    let (net_in, net_out) = (net_in.unwrap_or(0), net_out.unwrap_or(0));
+    let (blk_read, blk_write) = (blk_read.unwrap_or(0), blk_write.unwrap_or(0));
+    let pids = field(&stat.pids).parse::<u64>().unwrap_or(0);
+    Sample {
+        name: stat.name.clone().or_else(|| stat.id.clone()).unwrap_or_default(),
+        // Shortened like `docker ps` and the API backend, `--no-trunc` prints the full ID
+        id: stat.id.as_ref().map(|id| id.chars().take(12).collect()).or_else(|| stat.name.clone()).unwrap_or_default(),
+        cpu,
+        mem_usage,
+        mem_limit,
+        mem_percent,
+        net_in,
+        net_out,
+        block_read: blk_read,
+        block_write: blk_write,
+        pids,
+        read_at: Some(Utc::now()),
+        ..Sample::default()
     }
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    // Argument parsing
-    let args: Vec<String> = env::args().collect();
-    let mut target = "prometheus".to_string();
-    let mut port = 9187;
-    let mut host = "localhost".to_string();
-    let mut db = "metrics".to_string();
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--target" => { if i+1 < args.len() { target = args[i+1].clone(); i += 1; } }
-            "--port" | "-p" => { if i+1 < args.len() { port = args[i+1].parse().unwrap_or(9187); i += 1; } }
-            "--host" => { if i+1 < args.len() { host = args[i+1].clone(); i += 1; } }
-            "--db" => { if i+1 < args.len() { db = args[i+1].clone(); i += 1; } }
-            "-h" | "--help" => { usage(); return Ok(()); }
-            _ => {}
-        }
-        i += 1;
-    }
-
-    // Shared Docker stats reader
-    let mut child = Command::new("docker")
-        .arg("stats")
-        .arg("--format")
-        .arg("{{json .}}")
+/// HTML of the `/` page: links to the endpoints, the version and what is collected from where
+fn landing_page(config: &Config) -> String {
+    let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;");
+    let source = match &config.stats_source {
+        Some(path) => format!("recorded stats from {}", escape(path)),
+        None => {
+            let hosts: Vec<String> = config.docker_targets().iter()
+                .map(|docker| escape(docker.host.as_deref().unwrap_or("the default docker daemon")))
+                .collect();
+            format!("{} via the {} backend", hosts.join(", "), escape(&config.backend))
+        }
+    };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Docker Metrics Exporter</title></head>\n<body>\n\
+         <h1>Docker Metrics Exporter</h1>\n\
+         <p>Version {}, target {}, collecting from {}.</p>\n\
+         <ul>\n<li><a href=\"{}\">{}</a></li>\n<li><a href=\"healthz\">/healthz</a></li>\n</ul>\n\
+         </body>\n</html>\n",
+        env!("CARGO_PKG_VERSION"), escape(&config.target), source,
+        // Relative, so the links work behind a proxy that adds a prefix
+        escape(&config.metrics_path[1..]), escape(&config.metrics_path),
+    )
+}
+
+/// Whether the `Authorization` header `auth` is the `expected` one, always if none is expected
+fn authorized(expected: Option<&str>, auth: Option<&str>) -> bool {
+    expected.is_none_or(|expected| auth.is_some_and(|auth| constant_time_eq(auth.as_bytes(), expected.as_bytes())))
+}
+
+/// 401 asking for the `--metrics-user` credentials
+fn unauthorized() -> Result<warp::http::Response<Vec<u8>>, warp::http::Error> {
+    warp::http::Response::builder()
+        .status(warp::http::StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Basic realm=\"docker_metrics_exporter\"")
+        .body(Vec::new())
+}
+
+/// Compare two byte strings in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether an `Accept-Encoding` header allows gzip, i.e. lists `gzip` (or
+/// `*`) without `q=0`
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut parts = encoding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0.0));
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Which containers are exported (`--filter`) and under which name (`--relabel`)
+#[derive(Debug)]
+struct NameRules {
+    filters: Vec<Regex>,
+    /// (regex, replacement), applied in order
+    relabel: Vec<(Regex, String)>,
+    /// `--dedup-names`: suffix the names of containers in a [`NameClashes`] clash
+    dedup: bool,
+}
+impl NameRules {
+    fn new(config: &Config) -> Self {
+        NameRules { filters: config.filters(), relabel: config.relabel(), dedup: config.dedup_names }
+    }
+
+    /// True if no filters are configured or any of them matches the container name
+    fn matches(&self, name: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|f| f.is_match(name))
+    }
+
+    /// `name` rewritten by every rule in turn. A name that ends up empty is
+    /// kept as it was, as an empty `name` label would be dropped.
+    fn relabel(&self, name: &str) -> String {
+        let relabeled = self.relabel.iter()
+            .fold(name.to_string(), |name, (regex, replacement)| regex.replace_all(&name, replacement.as_str()).into_owned());
+        if relabeled.is_empty() { name.to_string() } else { relabeled }
+    }
+}
+
+/// How long [`NameClashes`] remembers a container after its last sample
+const CLASH_MEMORY: Duration = Duration::from_secs(300);
+
+/// A container as last seen under its name by [`NameClashes`]
+struct NameOwner {
+    id: String,
+    /// Number of the sample it was last seen in
+    seq: u64,
+    seen: Instant,
+    /// Seen alongside another container of the same name, warned about once
+    clashed: bool,
+    /// Named apart with `--dedup-names`, it wasn't the first with the name
+    suffixed: bool,
+}
+
+/// Finds running containers that share a name on the same host, whose
+/// series would overwrite each other where the name is all that tells
+/// them apart (in Graphite paths, or after `--relabel` without `id`).
+/// Two containers clash once both reported since the previous sample of
+/// one of them; a container replaced under its name by a new one stops
+/// reporting and isn't a clash.
+#[derive(Default)]
+struct NameClashes {
+    /// By (host, name before `--relabel`)
+    owners: HashMap<(String, String), Vec<NameOwner>>,
+    seq: u64,
+}
+impl NameClashes {
+    /// Record `sample` and return whether it has to be named apart from
+    /// another container of its name, logging a warning when a clash is found
+    fn observe(&mut self, sample: &Sample, dedup: bool) -> bool {
+        self.seq += 1;
+        let owners = self.owners.entry((sample.host.clone(), sample.name.clone())).or_default();
+        let Some(index) = owners.iter().position(|owner| owner.id == sample.id) else {
+            owners.push(NameOwner { id: sample.id.clone(), seq: self.seq, seen: Instant::now(), clashed: false, suffixed: false });
+            return false;
+        };
+        let prev = owners[index].seq;
+        let clash = owners.iter().any(|owner| owner.id != sample.id && owner.seq > prev);
+        let known = owners.iter().any(|owner| owner.clashed);
+        let owner = &mut owners[index];
+        owner.seq = self.seq;
+        owner.seen = Instant::now();
+        if clash && !owner.clashed {
+            owner.clashed = true;
+            owner.suffixed = index != 0;
+            if dedup && owner.suffixed {
+                warn!("Several running containers are named {}{}, {} is exported as {}", sample.name, on_host(&sample.host), sample.id, dedup_name(&sample.name, &sample.id));
+            } else if !dedup && !known {
+                warn!("Several running containers are named {}{}, their series can overwrite each other, see --dedup-names", sample.name, on_host(&sample.host));
+            }
+        }
+        let suffixed = owner.suffixed;
+        // Whoever didn't report since is gone
+        owners.retain(|owner| owner.seq > prev || owner.id == sample.id);
+        dedup && suffixed
+    }
+
+    /// Forget the containers not seen for [`CLASH_MEMORY`]
+    fn evict(&mut self) {
+        for owners in self.owners.values_mut() {
+            owners.retain(|owner| owner.seen.elapsed() <= CLASH_MEMORY);
+        }
+        self.owners.retain(|_, owners| !owners.is_empty());
+    }
+}
+
+/// `name` told apart by the start of the container `id`, the same for the container's lifetime
+fn dedup_name(name: &str, id: &str) -> String {
+    format!("{}-{}", name, id.chars().take(8).collect::<String>())
+}
+
+/// ` on HOST` for log messages, empty for the default daemon
+fn on_host(host: &str) -> String {
+    if host.is_empty() { String::new() } else { format!(" on {}", host) }
+}
+
+/// The rules the pipeline currently applies, replaced by `POST /-/reload`
+type SharedNameRules = Arc<std::sync::RwLock<NameRules>>;
+
+/// `POST /-/reload`: read the options again (the `--config` file, with the
+/// command line `args` still taking precedence) and swap in the new
+/// `--filter`, `--relabel` and `--dedup-names` rules. Everything else stays
+/// as the exporter was started, `--label-map` (`label_map` at the start)
+/// can't change at all as it decides the label names of the series.
+fn reload_rules(args: &[String], label_map: &[(String, String)], names: &SharedNameRules) -> Result<String, String> {
+    let config = Config::from_args(args)?;
+    let Some(path) = &config.config_file else {
+        return Err("Started without --config, there is nothing to reload".to_string());
+    };
+    if config.label_map() != label_map {
+        return Err("--label-map changes the labels of the series and needs a restart".to_string());
+    }
+    let rules = NameRules::new(&config);
+    let summary = format!("Reloaded {} filters and {} relabel rules", rules.filters.len(), rules.relabel.len());
+    *names.write().unwrap() = rules;
+    info!("{} from {}", summary, path);
+    Ok(summary)
+}
+
+/// How often the Pushgateway or OTLP collector is updated when streaming (no `--interval`)
+const DEFAULT_PUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Git commit the exporter was built from, `unknown` outside a git checkout
+const GIT_COMMIT: &str = env!("DOCKER_EXPORTER_GIT_COMMIT");
+
+/// `-V` output: version, git commit and build time
+fn version_info() -> String {
+    let built = env!("DOCKER_EXPORTER_BUILD_TIMESTAMP").parse().ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .map_or_else(|| "unknown".to_string(), |time| time.to_rfc3339());
+    format!("docker_metrics_exporter {} (commit {}, built {})", env!("CARGO_PKG_VERSION"), GIT_COMMIT, built)
+}
+
+/// A registry with the exporter's own metrics, for the container metrics to be added to
+fn exporter_registry(config: &Config, stats: &CollectorStats) -> Registry {
+    // The `--node-label` labels go on everything gathered from it
+    let node_labels = Some(config.node_labels().into_iter().collect()).filter(|labels: &HashMap<String, String>| !labels.is_empty());
+    let registry = Registry::new_custom(None, node_labels).unwrap();
+    registry.register(Box::new(stats.parse_errors.clone())).unwrap();
+    registry.register(Box::new(stats.scrape_errors.clone())).unwrap();
+    if config.backend == "cli" {
+        registry.register(Box::new(stats.format_ok.clone())).unwrap();
+        registry.register(Box::new(stats.warming_up.clone())).unwrap();
+    }
+    registry.register(Box::new(stats.cpu_clamped.clone())).unwrap();
+    registry.register(Box::new(stats.write_queue_depth.clone())).unwrap();
+    if config.has_target("influxdb") {
+        registry.register(Box::new(stats.influx_write_failures.clone())).unwrap();
+        registry.register(Box::new(stats.influx_circuit_open.clone())).unwrap();
+    }
+    if config.has_target("kafka") {
+        registry.register(Box::new(stats.kafka_delivery_errors.clone())).unwrap();
+    }
+    if config.targets().len() > 1 {
+        registry.register(Box::new(stats.target_samples_dropped.clone())).unwrap();
+    }
+    let build_info = prometheus::IntGaugeVec::new(
+        prometheus::Opts::new("exporter_build_info", "Always 1, labeled with the version and git commit of the exporter").namespace(config.metric_prefix.as_str()),
+        &["version", "commit"],
+    ).unwrap();
+    build_info.with_label_values(&[env!("CARGO_PKG_VERSION"), GIT_COMMIT]).set(1);
+    registry.register(Box::new(build_info)).unwrap();
+    // CPU, memory and open fds of the exporter itself, read from /proc
+    if !config.no_self_metrics {
+        registry.register(Box::new(prometheus::process_collector::ProcessCollector::for_self())).unwrap();
+    }
+    registry
+}
+
+/// Whether an executable `name` exists in one of the `PATH` directories
+fn in_path(name: &str, path: &str) -> bool {
+    env::split_paths(path).any(|dir| is_executable(&dir.join(name)))
+}
+
+fn is_executable(file: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(file).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// Which docker daemon the CLI talks to
+#[derive(Clone, Debug, Default)]
+struct DockerTarget {
+    /// The CLI run, `docker` or a compatible one like `podman`
+    binary: String,
+    host: Option<String>,
+    context: Option<String>,
+    /// `--ssh` destination the CLI is run on
+    ssh: Option<String>,
+    /// `--ssh-identity` key file
+    ssh_identity: Option<String>,
+}
+impl DockerTarget {
+    /// Value of the `host` label for samples of this daemon
+    fn name(&self) -> String {
+        self.host.clone().unwrap_or_default()
+    }
+
+    /// ` on HOST` for log messages, empty for the default daemon
+    fn on_host(&self) -> String {
+        self.host.as_ref().or(self.ssh.as_ref()).map(|host| format!(" on {}", host)).unwrap_or_default()
+    }
+
+    /// `docker args...` preconfigured for this daemon. With `--ssh` it is run
+    /// by `ssh` on the remote host, which then exits (and is restarted like
+    /// any other failed `docker stats`) when the connection drops.
+    fn command<S: AsRef<str>>(&self, args: &[S]) -> Command {
+        let Some(destination) = &self.ssh else {
+            let mut cmd = Command::new(&self.binary);
+            if let Some(host) = &self.host {
+                cmd.env("DOCKER_HOST", host);
+            }
+            if let Some(context) = &self.context {
+                cmd.arg("--context").arg(context);
+            }
+            cmd.args(args.iter().map(AsRef::as_ref));
+            return cmd;
+        };
+        let mut cmd = Command::new("ssh");
+        // No prompts nobody can answer, and a dead connection noticed within 45s
+        cmd.args(["-n", "-T", "-o", "BatchMode=yes", "-o", "ServerAliveInterval=15", "-o", "ServerAliveCountMax=3"]);
+        if let Some(identity) = &self.ssh_identity {
+            cmd.arg("-i").arg(identity).args(["-o", "IdentitiesOnly=yes"]);
+        }
+        // The remote shell splits the command line again, so e.g. `{{json .}}` stays one argument
+        let remote = std::iter::once(self.binary.as_str()).chain(args.iter().map(AsRef::as_ref)).map(shell_quote).collect::<Vec<_>>();
+        cmd.arg("--").arg(destination).arg(remote.join(" "));
+        cmd
+    }
+}
+
+/// `arg` quoted for a POSIX shell, as is if it has nothing the shell would interpret
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Samples the collectors may queue for the pipeline. When the only target
+/// falls behind, e.g. while InfluxDB writes are retried, the collectors wait
+/// for room and `docker stats` blocks on its full pipe instead of the
+/// exporter buffering without bound. With several targets the ones behind
+/// drop samples instead, see [`Fanout`].
+const SAMPLE_QUEUE: usize = 1024;
+
+/// Feed collected samples that pass the filters to `sink`, capped by `clamp`, relabeled and
+/// completed with the container details from the inspector of their host, until `stop` fires.
+/// Samples waiting for the details of a new container are recorded once they are there.
+/// The sink is flushed every `flush_every` and finished before returning.
+/// `queue_depth` follows the samples still queued in `rx`.
+#[allow(clippy::too_many_arguments)]
+async fn run_pipeline<S: Sink>(
+    mut sink: S,
+    mut rx: mpsc::Receiver<Sample>,
+    names: SharedNameRules,
+    mut clamp: CpuClamp,
+    mut inspectors: Inspectors,
+    flush_every: Duration,
+    last_read: Arc<std::sync::Mutex<Instant>>,
+    mut stop: oneshot::Receiver<()>,
+    queue_depth: IntGauge,
+) {
+    let mut flush_tick = tokio::time::interval(flush_every);
+    let mut clashes = NameClashes::default();
+    loop {
+        tokio::select! {
+            sample = rx.recv() => {
+                let Some(mut sample) = sample else { break };
+                queue_depth.set(rx.len() as i64);
+                *last_read.lock().unwrap() = Instant::now();
+                // Not held across the clamp, a reload may wait for it
+                let matches = names.read().unwrap().matches(&sample.name);
+                if matches {
+                    clamp.apply(&mut sample).await;
+                    if let Some(mut sample) = inspectors.enrich(sample) {
+                        sample.name = relabel(&sample, &names, &mut clashes);
+                        sink.record(&sample).await;
+                    }
+                }
+            }
+            ready = inspectors.ready() => {
+                for mut sample in ready {
+                    sample.name = relabel(&sample, &names, &mut clashes);
+                    sink.record(&sample).await;
+                }
+            }
+            _ = flush_tick.tick() => {
+                clashes.evict();
+                // Kept before relabeling, as they were passed the filter. Recorded once,
+                // the Prometheus based sinks hold their series for a while
+                for mut sample in inspectors.oom_killed() {
+                    sample.name = relabel(&sample, &names, &mut clashes);
+                    sink.record(&sample).await;
+                }
+                sink.flush().await;
+            }
+            _ = &mut stop => break,
+        }
+    }
+    for mut sample in inspectors.settle().await {
+        sample.name = relabel(&sample, &names, &mut clashes);
+        sink.record(&sample).await;
+    }
+    sink.finish().await;
+}
+
+/// The exported name of `sample`, relabeled and made unique if `--dedup-names` asks for it
+fn relabel(sample: &Sample, names: &SharedNameRules, clashes: &mut NameClashes) -> String {
+    let rules = names.read().unwrap();
+    let relabeled = rules.relabel(&sample.name);
+    match clashes.observe(sample, rules.dedup) {
+        true => dedup_name(&relabeled, &sample.id),
+        false => relabeled,
+    }
+}
+
+/// Resolves once SIGINT (Ctrl-C) or SIGTERM is received, or at the
+/// `--max-runtime` `deadline`
+async fn shutdown_signal(deadline: Option<Instant>) {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+    let runtime = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+        _ = runtime => {}
+    }
+}
+
+/// The exporter's own counters, updated by the collectors and the InfluxDB sink
+#[derive(Clone)]
+struct CollectorStats {
+    /// Stats lines or API responses that could not be parsed
+    parse_errors: IntCounter,
+    /// Failed `docker stats` runs and Docker API requests
+    scrape_errors: IntCounter,
+    /// Stats lines skipped by [`warming_up`]
+    warming_up: IntCounter,
+    /// Result of [`check_format`] on the first line of the last `docker stats` run
+    format_ok: IntGauge,
+    /// CPU readings capped at `--cpu-max-clamp`, see [`inspect::CpuClamp`]
+    cpu_clamped: IntCounter,
+    /// Batches [`InfluxSink`] gave up on, exported with `--target prometheus+influxdb`
+    influx_write_failures: IntCounter,
+    /// Batches [`InfluxSink`] dropped without trying while InfluxDB kept failing
+    influx_circuit_open: IntCounter,
+    /// Messages the Kafka producer could not queue or deliver
+    kafka_delivery_errors: IntCounter,
+    /// Samples a target missed because its queue in [`Fanout`] was full, by target
+    target_samples_dropped: IntCounterVec,
+    /// Samples waiting for the sink, at most [`SAMPLE_QUEUE`]
+    write_queue_depth: IntGauge,
+}
+impl CollectorStats {
+    /// Named `{prefix}_...` when registered
+    fn new(prefix: &str) -> Self {
+        let opts = |name: &str, help: &str| prometheus::Opts::new(name, help).namespace(prefix);
+        let format_ok = IntGauge::with_opts(opts("stats_format_ok", "1 if the last docker stats run started with a line in the expected format")).unwrap();
+        format_ok.set(1);
+        CollectorStats {
+            parse_errors: IntCounter::with_opts(opts("stats_parse_errors_total", "Stats lines that could not be parsed")).unwrap(),
+            scrape_errors: IntCounter::with_opts(opts("scrape_errors_total", "Failed docker stats runs or Docker API requests")).unwrap(),
+            warming_up: IntCounter::with_opts(opts("stats_warming_up_total", "Stats lines skipped because docker had no readings for the container yet")).unwrap(),
+            format_ok,
+            cpu_clamped: IntCounter::with_opts(opts("cpu_clamped_total", "CPU readings above --cpu-max-clamp that were capped")).unwrap(),
+            influx_write_failures: IntCounter::with_opts(opts("influx_write_failures_total", "InfluxDB batches given up after repeated write failures")).unwrap(),
+            influx_circuit_open: IntCounter::with_opts(opts("influx_circuit_open_total", "InfluxDB batches dropped without a write while the circuit breaker was open")).unwrap(),
+            kafka_delivery_errors: IntCounter::with_opts(opts("kafka_delivery_errors_total", "Kafka messages that could not be queued or delivered")).unwrap(),
+            target_samples_dropped: IntCounterVec::new(opts("target_samples_dropped_total", "Samples a target missed because it fell behind the others"), &["target"]).unwrap(),
+            write_queue_depth: IntGauge::with_opts(opts("write_queue_depth", "Samples collected but not yet handed to the target")).unwrap(),
+        }
+    }
+}
+
+/// Initial delay before re-spawning `docker stats` or retrying the Docker API
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How the output read by [`forward_stats`] ended
+struct Forwarded {
+    /// At least one line was read
+    got_data: bool,
+    /// The watchdog gave up waiting for the next line
+    stalled: bool,
+}
+
+/// How a single `docker stats` run ended
+struct StatsRun {
+    /// At least one line was read
+    got_data: bool,
+    /// Killed by the watchdog, see [`StatsFormat::watchdog`]
+    stalled: bool,
+    /// Exit status, or why the process could not be started or waited for
+    exit: Result<std::process::ExitStatus, String>,
+}
+
+/// Raw lines `--record` may queue before further ones are dropped
+const RECORD_QUEUE: usize = 4096;
+
+/// Copy of every raw `docker stats` line for `--record`, so the output behind
+/// a parsing bug can be replayed with `--stats-source`. A background task
+/// writes the lines; the collectors never wait for the disk.
+#[derive(Clone)]
+struct Recorder(mpsc::Sender<String>);
+impl Recorder {
+    /// Truncate `path` and start the writer, which ends once all clones are
+    /// dropped and the remaining lines are written
+    async fn create(path: &str) -> Result<(Self, tokio::task::JoinHandle<()>), Error> {
+        let file = tokio::fs::File::create(path).await
+            .map_err(|source| Error::Record { path: path.to_string(), source })?;
+        let (tx, mut rx) = mpsc::channel::<String>(RECORD_QUEUE);
+        let path = path.to_string();
+        let writer = tokio::spawn(async move {
+            let mut file = tokio::io::BufWriter::new(file);
+            while let Some(line) = rx.recv().await {
+                let mut written = file.write_all(line.as_bytes()).await;
+                // Flush whenever the queue runs dry so the file is complete while streaming
+                if written.is_ok() && rx.is_empty() {
+                    written = file.flush().await;
+                }
+                if let Err(e) = written {
+                    error!("Cannot write to {}: {}, recording stopped", path, e);
+                    return;
+                }
+            }
+        });
+        Ok((Recorder(tx), writer))
+    }
+
+    fn record(&self, line: &str) {
+        if self.0.try_send(format!("{}\n", line)).is_err() {
+            debug!("Recording is behind, dropping a docker stats line");
+        }
+    }
+}
+
+/// Longest stats line that is still joined with the next one when it ends
+/// in the middle of a JSON object
+const MAX_PARTIAL_LINE: usize = 64 * 1024;
+
+/// Forward the samples of newline delimited `docker stats` JSON, tagged with
+/// `host`, to `tx` until the output ends. Lines that are not valid stats JSON
+/// are counted in `stats`, numbers are parsed according to the locale of
+/// `format`, and the first line is checked with [`check_format`]. A line that ends in the middle
+/// of a JSON object is joined with the next one before it is given up on.
+/// Every line is passed to `recorder` as read. With a watchdog in `format`
+/// reading stops once no line arrived within its timeout.
+/// Returns `None` once `tx` is closed.
+async fn forward_stats<R: AsyncRead + Unpin>(
+    output: R,
+    host: &str,
+    tx: &mpsc::Sender<Sample>,
+    stats: &CollectorStats,
+    format: &StatsFormat,
+    recorder: Option<&Recorder>,
+) -> Option<Forwarded> {
+    let mut reader = BufReader::new(output).lines();
+    let mut got_data = false;
+    let mut stalled = false;
+    // Start of a JSON object that was split across lines
+    let mut partial = String::new();
+    loop {
+        let next = match format.watchdog {
+            Some(timeout) => match tokio::time::timeout(timeout, reader.next_line()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    stalled = true;
+                    break;
+                }
+            },
+            None => reader.next_line().await,
+        };
+        match next {
+            Ok(Some(line)) => {
+                if let Some(recorder) = recorder {
+                    recorder.record(&line);
+                }
+                if format.raw {
+                    // Escaped, so control sequences show up instead of acting on the terminal
+                    match host {
+                        "" => eprintln!("{:?}", line),
+                        host => eprintln!("{}: {:?}", host, line),
+                    }
+                }
+                let line = strip_terminal_noise(&line);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if partial.is_empty() && is_header(line) {
+                    debug!("Skipping docker stats header: {}", line);
+                    continue;
+                }
+                let line = std::mem::take(&mut partial) + line;
+                let parsed = serde_json::from_str::<DockerStat>(&line);
+                if let Err(e) = &parsed
+                    && e.is_eof()
+                    && line.len() < MAX_PARTIAL_LINE
+                {
+                    debug!("Incomplete docker stats line, waiting for the rest: {}", line);
+                    partial = line;
+                    continue;
+                }
+                if !got_data {
+                    stats.format_ok.set(check_format(&line, format.template == DEFAULT_STATS_FORMAT) as i64);
+                }
+                got_data = true;
+                match parsed {
+                    Ok(stat) if stat.id.is_none() && stat.name.is_none() => {
+                        stats.parse_errors.inc();
+                        warn!("Skipping docker stats line without ID and Name");
+                        debug!("Unparseable line: {}", line);
+                    }
+                    Ok(stat) if warming_up(&stat) => {
+                        stats.warming_up.inc();
+                        debug!("Skipping docker stats line without readings yet: {}", line);
+                    }
+                    Ok(stat) => {
+                        let sample = Sample { host: host.to_string(), ..parse_stat(&stat, format.locale) };
+                        tx.send(sample).await.ok()?;
+                    }
+                    Err(e) => {
+                        stats.parse_errors.inc();
+                        warn!("Skipping unparseable docker stats line: {}", e);
+                        debug!("Unparseable line: {}", line);
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error reading docker stats output: {}", e);
+                break;
+            }
+        }
+    }
+    if !partial.is_empty() {
+        stats.parse_errors.inc();
+        warn!("docker stats output ended in the middle of a line");
+        debug!("Incomplete line: {}", partial);
+    }
+    Some(Forwarded { got_data, stalled })
+}
+
+/// The `docker stats` command printing `template` for every container, once
+/// if `no_stream`
+fn stats_command(docker: &DockerTarget, no_stream: bool, template: &str) -> Command {
+    let mut args = vec!["stats"];
+    if no_stream {
+        args.push("--no-stream");
+    }
+    args.extend(["--no-trunc", "--format", template]);
+    // Without a terminal (and with TERM=dumb for docker versions that look at
+    // it anyway) docker stats has no reason to clear the screen or move the cursor
+    let mut cmd = docker.command(&args);
+    cmd.env("TERM", "dumb")
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn docker stats");
+        .kill_on_drop(true);
+    cmd
+}
+
+/// Run `docker stats` once, with `--no-stream` if `no_stream`, and forward
+/// its output with [`forward_stats`].
+/// Returns `None` once `tx` is closed.
+async fn run_stats(
+    docker: &DockerTarget,
+    tx: &mpsc::Sender<Sample>,
+    no_stream: bool,
+    stats: &CollectorStats,
+    format: &StatsFormat,
+    recorder: Option<&Recorder>,
+) -> Option<StatsRun> {
+    let spawned = stats_command(docker, no_stream, &format.template).spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => return Some(StatsRun { got_data: false, stalled: false, exit: Err(format!("failed to spawn: {}", e)) }),
+    };
     let stdout = child.stdout.take().expect("No stdout");
-    let mut reader = BufReader::new(stdout).lines();
+    let Forwarded { got_data, stalled } = forward_stats(stdout, &docker.name(), tx, stats, format, recorder).await?;
+    if stalled {
+        // Most likely a wedged daemon, the process itself is still there
+        let _ = child.kill().await;
+    }
+    debug!("docker stats output ended, waiting for the process to exit");
+    Some(StatsRun { got_data, stalled, exit: child.wait().await.map_err(|e| format!("failed: {}", e)) })
+}
 
-    if target == "prometheus" {
-        // Setup Prometheus exporter
-        let registry = Registry::new();
-        let metrics = Arc::new(Mutex::new(Metrics::new(&registry)));
-        let metrics_clone = Arc::clone(&metrics);
+/// Read recorded `docker stats --format '{{json .}}'` output from `path` (stdin
+/// for `-`) instead of running docker, for `--stats-source`. Returns whether
+/// the source could be read.
+async fn replay_stats(path: String, tx: mpsc::Sender<Sample>, stats: CollectorStats, format: StatsFormat) -> bool {
+    let forwarded = if path == "-" {
+        forward_stats(tokio::io::stdin(), "", &tx, &stats, &format, None).await
+    } else {
+        match tokio::fs::File::open(&path).await {
+            Ok(file) => forward_stats(file, "", &tx, &stats, &format, None).await,
+            Err(e) => {
+                error!("Cannot read stats source {}: {}", path, e);
+                return false;
+            }
+        }
+    };
+    if forwarded.is_some_and(|forwarded| !forwarded.got_data) {
+        warn!("Stats source {} is empty", path);
+    }
+    true
+}
 
-        // Spawn update task
-        tokio::spawn(async move {
-            while let Ok(Some(line)) = reader.next_line().await {
-                if let Ok(stat) = serde_json::from_str::<DockerStat>(&line) {
-                    let mut m = metrics_clone.lock().await;
-                    m.update(&stat);
+/// Keep `docker stats` running and forward the parsed samples to `tx`.
+/// Whenever the child exits (e.g. during a docker daemon restart) it is
+/// re-spawned after an exponential backoff, which resets on a successful read.
+///
+/// With an `interval`, `docker stats --no-stream` is run once per interval
+/// instead of keeping a single streaming process alive.
+///
+/// Failed runs are counted in `stats`, see [`run_stats`] for the rest.
+async fn supervise_stats(
+    docker: DockerTarget,
+    tx: mpsc::Sender<Sample>,
+    interval: Option<Duration>,
+    max_backoff: Duration,
+    stats: CollectorStats,
+    format: StatsFormat,
+    recorder: Option<Recorder>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let Some(run) = run_stats(&docker, &tx, interval.is_some(), &stats, &format, recorder.as_ref()).await else { return };
+        if run.got_data {
+            backoff = INITIAL_BACKOFF;
+        }
+        match run.exit {
+            _ if run.stalled => warn!(
+                "docker stats{} printed nothing for {:?}, killed it, restarting in {:?}",
+                docker.on_host(), format.watchdog.unwrap_or_default(), backoff,
+            ),
+            Ok(status) if status.success() && run.got_data && interval.is_some() => {
+                tokio::time::sleep(interval.unwrap()).await;
+                continue;
+            }
+            Ok(status) => warn!("docker stats{} exited ({}), restarting in {:?}", docker.on_host(), status, backoff),
+            Err(e) => error!("docker stats{} {}, retrying in {:?}", docker.on_host(), e, backoff),
+        }
+        stats.scrape_errors.inc();
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// Collect a single `docker stats --no-stream` snapshot for `--once`.
+/// Returns whether it succeeded.
+async fn collect_once(
+    docker: DockerTarget,
+    tx: mpsc::Sender<Sample>,
+    stats: CollectorStats,
+    format: StatsFormat,
+    recorder: Option<Recorder>,
+) -> bool {
+    match run_stats(&docker, &tx, true, &stats, &format, recorder.as_ref()).await {
+        Some(StatsRun { stalled: true, .. }) => {
+            error!("docker stats{} printed nothing for {:?}, killed it", docker.on_host(), format.watchdog.unwrap_or_default());
+            false
+        }
+        Some(StatsRun { exit: Ok(status), .. }) if status.success() => true,
+        Some(StatsRun { exit: Ok(status), .. }) => {
+            error!("docker stats{} exited ({})", docker.on_host(), status);
+            false
+        }
+        Some(StatsRun { exit: Err(e), .. }) => {
+            error!("docker stats{} {}", docker.on_host(), e);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Listen on the Unix domain socket `path`, replacing a socket left behind by
+/// an earlier run that wasn't shut down cleanly
+fn bind_unix_socket(path: &str) -> Result<tokio::net::UnixListener, Error> {
+    use std::os::unix::fs::FileTypeExt;
+    let error = |e: std::io::Error| Error::Bind { addr: format!("unix://{}", path), reason: e.to_string() };
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path).map_err(error)?;
+    }
+    tokio::net::UnixListener::bind(path).map_err(error)
+}
+
+/// Report the stopped containers of `source` once or every interval, see [`stopped`]
+fn spawn_stopped(
+    config: &Config,
+    source: inspect::Source,
+    host: String,
+    tx: mpsc::Sender<Sample>,
+    stats: CollectorStats,
+) -> tokio::task::JoinHandle<bool> {
+    if config.once {
+        tokio::spawn(stopped::collect_once(source, host, tx, stats))
+    } else {
+        let interval = config.interval();
+        tokio::spawn(async move { stopped::supervise_stopped(source, host, tx, interval, stats).await; true })
+    }
+}
+
+/// InfluxDB sink of `--target influxdb` and `prometheus+influxdb`, a bucket means the 2.x API
+fn influx_sink(config: &Config, stats: CollectorStats, extra_labels: &[String], stale_timeout: Duration) -> Result<InfluxSink, Error> {
+    let url = config.influx_url();
+    let http = InfluxApi::http_client(config.influx_ca_cert.as_deref(), config.influx_insecure).map_err(Error::Influx)?;
+    let api = if let (Some(org), Some(bucket)) = (&config.influx_org, &config.influx_bucket) {
+        if config.influx_user.is_some() || config.influx_password.is_some() {
+            warn!("InfluxDB 2.x authenticates with --influx-token, ignoring --influx-user/--influx-password");
+        }
+        // Presence of the token is checked by Config::validate
+        InfluxApi::v2(http, &url, org, bucket, config.influx_token.as_deref().unwrap_or_default(), config.influx_precision)
+    } else {
+        let (token, user, password) = (config.influx_token.as_deref(), config.influx_user.as_deref(), config.influx_password.as_deref());
+        match (token, user, password) {
+            (Some(token), user, password) => {
+                if user.is_some() || password.is_some() {
+                    warn!("Both InfluxDB token and username/password given, using the token");
                 }
+                InfluxApi::v1(http, &url, &config.db, Some(token), None, config.influx_precision)
             }
-        });
+            (None, Some(user), password) => InfluxApi::v1(http, &url, &config.db, None, Some((user, password.unwrap_or_default())), config.influx_precision),
+            (None, None, password) => {
+                if password.is_some() {
+                    warn!("--influx-password given without --influx-user, ignoring it");
+                }
+                InfluxApi::v1(http, &url, &config.db, None, None, config.influx_precision)
+            }
+        }
+    };
+    if config.influx_insecure {
+        warn!("Not verifying the certificate of {} (--influx-insecure)", url);
+    }
+    Ok(InfluxSink::new(
+        api.compressed(config.influx_compress),
+        config.batch_size,
+        &config.measurement,
+        stats,
+        extra_labels,
+        &config.node_labels(),
+        config.dead_letter_file.as_deref(),
+        &config.disable_metrics,
+        stale_timeout,
+        config.influx_timestamp,
+    ))
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args).await {
+        Ok(()) => {}
+        Err(e @ Error::Config(_)) => {
+            // The logger isn't set up yet
+            eprintln!("{}", e);
+            usage();
+            std::process::exit(e.exit_code());
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
+
+async fn run(args: &[String]) -> Result<(), Error> {
+    let config = Config::from_args(args).map_err(Error::Config)?;
+    if config.help {
+        usage();
+        return Ok(());
+    }
+    if config.version {
+        println!("{}", version_info());
+        return Ok(());
+    }
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(config.log_filter().as_str())).init();
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+
+    // Fail early instead of retrying a spawn that can't succeed
+    if config.backend == "cli" && config.stats_source.is_none() {
+        let path = env::var("PATH").unwrap_or_default();
+        // The remote CLI can only be found by trying
+        let binary = if config.ssh.is_some() { "ssh" } else { config.runtime_binary.as_str() };
+        // A path is run as is, a name is looked up in PATH
+        let found = match binary.contains('/') {
+            true => is_executable(std::path::Path::new(binary)),
+            false => in_path(binary, &path),
+        };
+        if !found {
+            return Err(Error::Spawn { binary: binary.to_string(), path });
+        }
+        match &config.ssh {
+            Some(destination) => info!("Collecting with `{} stats` on {} over ssh", config.runtime_binary, destination),
+            None => info!("Collecting with `{} stats`", binary),
+        }
+    }
+
+    if config.check {
+        return if check::run(&config).await { Ok(()) } else { Err(Error::Check) };
+    }
+
+    // Spread the load on the daemon and the target when many exporters start together
+    let delay = config.startup_delay();
+    if !delay.is_zero() {
+        info!("Starting in {:.1}s (--startup-jitter)", delay.as_secs_f64());
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown_signal(None) => return Ok(()),
+        }
+    }
+    // Counted from here, so the whole --max-runtime is spent collecting
+    let deadline = config.max_runtime().map(|runtime| Instant::now() + runtime);
+
+    let interval = config.interval();
+    let max_backoff = config.max_backoff();
+    let stale_timeout = config.stale_timeout();
+    let health_timeout = config.health_timeout();
+    let names: SharedNameRules = Arc::new(std::sync::RwLock::new(NameRules::new(&config)));
+    let extra_labels = config.extra_labels();
+    let metrics_auth = config.metrics_auth();
+    let port = config.port;
+
+    // One Docker stats collector per host, restarted automatically if docker
+    // goes away. The supervisors only return once the pipeline is gone; with
+    // --once they end after one snapshot and tell whether it could be collected.
+    let (tx, rx) = mpsc::channel::<Sample>(SAMPLE_QUEUE);
+    let stats = CollectorStats::new(&config.metric_prefix);
+    let queue_depth = stats.write_queue_depth.clone();
+    let (recorder, recording) = match &config.record {
+        Some(path) => {
+            let (recorder, writer) = Recorder::create(path).await?;
+            (Some(recorder), Some(writer))
+        }
+        None => (None, None),
+    };
+    let mut clamp = CpuClamp::new(config.cpu_max_clamp, stats.clone());
+    let mut inspectors = Inspectors::new();
+    let mut supervisors = Vec::new();
+    let dockers = match &config.stats_source {
+        // Replayed stats have no daemon to inspect
+        Some(path) => {
+            supervisors.push(tokio::spawn(replay_stats(path.clone(), tx.clone(), stats.clone(), config.stats_format())));
+            Vec::new()
+        }
+        None => config.docker_targets(),
+    };
+    for docker in dockers {
+        let (tx, stats) = (tx.clone(), stats.clone());
+        let supervisor = if config.backend == "api" {
+            let endpoint = api::Endpoint::from_docker_host(docker.host.as_deref());
+            clamp.add_host(docker.name(), inspect::Source::Api(endpoint.clone()));
+            if config.needs_inspect() {
+                inspectors.add_host(docker.name(), inspect::Source::Api(endpoint.clone()), interval, config.cpu_mode, config.label_map());
+            }
+            if config.include_stopped {
+                supervisors.push(spawn_stopped(&config, inspect::Source::Api(endpoint.clone()), docker.name(), tx.clone(), stats.clone()));
+            }
+            if config.once {
+                tokio::spawn(api::collect_once(endpoint, docker.name(), tx, stats))
+            } else {
+                tokio::spawn(async move { api::supervise_api(endpoint, docker.name(), tx, interval, max_backoff, stats).await; true })
+            }
+        } else {
+            clamp.add_host(docker.name(), inspect::Source::Cli(docker.clone()));
+            if config.needs_inspect() {
+                inspectors.add_host(docker.name(), inspect::Source::Cli(docker.clone()), interval, config.cpu_mode, config.label_map());
+            }
+            if config.include_stopped {
+                supervisors.push(spawn_stopped(&config, inspect::Source::Cli(docker.clone()), docker.name(), tx.clone(), stats.clone()));
+            }
+            let (format, recorder) = (config.stats_format(), recorder.clone());
+            if config.once {
+                tokio::spawn(collect_once(docker, tx, stats, format, recorder))
+            } else {
+                tokio::spawn(async move { supervise_stats(docker, tx, interval, max_backoff, stats, format, recorder).await; true })
+            }
+        };
+        supervisors.push(supervisor);
+    }
+    // Only the collectors may keep the channel and the recording open
+    drop(tx);
+    drop(recorder);
+    let last_read = Arc::new(std::sync::Mutex::new(Instant::now()));
+    let (stop_tx, stop_rx) = oneshot::channel();
+    // With --once the exposition is printed instead of served
+    let mut once_registry = None;
+    let mut once_table = None;
 
+    // One sink per target, all fed by the same pipeline
+    let mut fanout = Fanout::new(stats.target_samples_dropped.clone());
+    // The registry and update lock of the Prometheus endpoint, if it is a target
+    let mut served = None;
+    if config.top.is_some() {
+        let sink = TopSink::default();
+        once_table = Some(sink.clone());
+        fanout.push(AnySink::Top(sink), Duration::from_secs(1));
+    } else {
+        for target in config.targets() {
+            match target {
+                "prometheus" => {
+                    // The pipeline also evicts containers that stopped reporting
+                    let registry = exporter_registry(&config, &stats);
+                    let update_lock = Arc::new(std::sync::Mutex::new(()));
+                    let sink = PrometheusSink {
+                        metrics: Metrics::new(&registry, &extra_labels, &config.metric_prefix, config.smooth, config.max_series, config.cardinality_alert_rate, &config.disable_metrics),
+                        stale_timeout,
+                        update_lock: Arc::clone(&update_lock),
+                    };
+                    fanout.push(AnySink::Prometheus(sink), Duration::from_secs(1).max(stale_timeout / 4));
+                    served = Some((registry, update_lock));
+                }
+                "influxdb" => {
+                    // Points are written in batches of up to `batch_size`, or whatever is pending every `batch_interval`
+                    let sink = influx_sink(&config, stats.clone(), &extra_labels, stale_timeout)?;
+                    fanout.push(AnySink::Influx(sink), config.batch_interval());
+                }
+                "pushgateway" => {
+                    // One push per interval, presence of the URL is checked by Config::validate
+                    let registry = exporter_registry(&config, &stats);
+                    let url = config.pushgateway_url.as_deref().unwrap_or_default();
+                    let metrics = Metrics::new(&registry, &extra_labels, &config.metric_prefix, config.smooth, config.max_series, config.cardinality_alert_rate, &config.disable_metrics);
+                    let sink = PushgatewaySink::new(registry, metrics, url, &config.job, &config.instance(), stale_timeout)
+                        .map_err(|reason| Error::Target { target: "pushgateway", reason })?;
+                    fanout.push(AnySink::Pushgateway(sink), interval.unwrap_or(DEFAULT_PUSH_INTERVAL));
+                }
+                #[cfg(feature = "remote-write")]
+                "remote-write" => {
+                    // One write per interval, presence of the URL is checked by Config::validate
+                    let registry = exporter_registry(&config, &stats);
+                    let url = config.remote_write_url.as_deref().unwrap_or_default();
+                    let metrics = Metrics::new(&registry, &extra_labels, &config.metric_prefix, config.smooth, config.max_series, config.cardinality_alert_rate, &config.disable_metrics);
+                    let sink = RemoteWriteSink::new(registry, metrics, url, stale_timeout)
+                        .map_err(|reason| Error::Target { target: "remote-write", reason })?;
+                    fanout.push(AnySink::RemoteWrite(sink), interval.unwrap_or(DEFAULT_PUSH_INTERVAL));
+                }
+                "statsd" => {
+                    let sink = StatsdSink::connect(&config.statsd_host, config.statsd_port, &extra_labels, &config.node_labels()).await
+                        .map_err(|e| Error::Target { target: "statsd", reason: format!("{}:{}: {}", config.statsd_host, config.statsd_port, e) })?;
+                    fanout.push(AnySink::Statsd(sink), Duration::from_secs(1));
+                }
+                "otlp" => {
+                    // One export per interval, presence of the endpoint is checked by Config::validate
+                    let endpoint = config.otlp_endpoint.as_deref().unwrap_or_default();
+                    let sink = OtlpSink::new(endpoint, &config.otlp_protocol, &config.metric_prefix, &extra_labels, &config.node_labels())
+                        .map_err(|reason| Error::Target { target: "otlp", reason })?;
+                    fanout.push(AnySink::Otlp(sink), interval.unwrap_or(DEFAULT_PUSH_INTERVAL));
+                }
+                "graphite" => {
+                    let sink = GraphiteSink::connect(&config.graphite_host, config.graphite_port, &extra_labels, &config.node_labels()).await
+                        .map_err(|e| Error::Target { target: "graphite", reason: format!("{}:{}: {}", config.graphite_host, config.graphite_port, e) })?;
+                    fanout.push(AnySink::Graphite(sink), Duration::from_secs(1));
+                }
+                "jsonl" => {
+                    let sink = JsonlSink::open(&config.output, config.max_file_bytes, &extra_labels, &config.node_labels()).await
+                        .map_err(|e| Error::Target { target: "jsonl", reason: format!("{}: {}", config.output, e) })?;
+                    fanout.push(AnySink::Jsonl(sink), config.flush_interval());
+                }
+                #[cfg(feature = "kafka")]
+                "kafka" => {
+                    // Presence of the brokers and topic is checked by Config::validate
+                    let (brokers, topic) = (config.kafka_brokers.as_deref().unwrap_or_default(), config.kafka_topic.as_deref().unwrap_or_default());
+                    let sink = KafkaSink::new(brokers, topic, &extra_labels, &config.node_labels(), stats.kafka_delivery_errors.clone())
+                        .map_err(|reason| Error::Target { target: "kafka", reason })?;
+                    fanout.push(AnySink::Kafka(sink), Duration::from_secs(1));
+                }
+                // Rejected by Config::validate
+                _ => unreachable!("unknown target {}", target),
+            }
+        }
+    }
+    let flush_every = fanout.flush_every();
+    let pipeline = tokio::spawn(run_pipeline(fanout, rx, Arc::clone(&names), clamp, inspectors, flush_every, Arc::clone(&last_read), stop_rx, queue_depth));
+
+    if let Some((registry, update_lock)) = served {
+        let once = registry.clone();
         // HTTP endpoint
-        let metrics_route = warp::path!("metrics").map(move || {
-            let metric_families = registry.gather();
-            let mut buffer = Vec::new();
-            TextEncoder::new().encode(&metric_families, &mut buffer).unwrap();
-            warp::http::Response::builder()
-                .header("Content-Type", "text/plain")
-                .body(String::from_utf8(buffer).unwrap())
+        let reload_auth = metrics_auth.clone();
+        // Segment by segment, warp::path! only takes literals
+        let metrics_path = config.metrics_path_segments().into_iter()
+            .fold(warp::any().boxed(), |path, segment| path.and(warp::path(segment)).boxed())
+            .and(warp::path::end());
+        let metrics_route = metrics_path
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("accept-encoding"))
+            .map(move |auth: Option<String>, accept: Option<String>, accept_encoding: Option<String>| {
+                if !authorized(metrics_auth.as_deref(), auth.as_deref()) {
+                    return unauthorized();
+                }
+                let openmetrics = accept.is_some_and(|accept| accept.contains("application/openmetrics-text"));
+                let (content_type, body) = {
+                    let _guard = update_lock.lock().unwrap();
+                    sink::render(&registry, openmetrics)
+                };
+                let response = warp::http::Response::builder()
+                    .header("Content-Type", content_type)
+                    .header("Vary", "Accept-Encoding");
+                if accept_encoding.is_some_and(|encodings| accepts_gzip(&encodings)) {
+                    response.header("Content-Encoding", "gzip").body(gzip(body.as_bytes()))
+                } else {
+                    response.body(body.into_bytes())
+                }
+            });
+
+        // Liveness: healthy as long as docker stats delivered data recently
+        let health_route = warp::path!("healthz").map(move || {
+            if last_read.lock().unwrap().elapsed() <= health_timeout {
+                warp::reply::with_status("ok", warp::http::StatusCode::OK)
+            } else {
+                warp::reply::with_status("stale", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+            }
         });
 
-        println!("Prometheus endpoint on http://0.0.0.0:{}/metrics", port);
-        warp::serve(metrics_route).run(([0,0,0,0], port)).await;
-    } else if target == "influxdb" {
-        // Setup InfluxDB client
-        let client = Client::new(format!("http://{}:{}", host, port), db);
-
-        // Main loop: read docker stats and write to InfluxDB
-        while let Ok(Some(line)) = reader.next_line().await {
-            if let Ok(stat) = serde_json::from_str::<DockerStat>(&line) {
-                let (cpu, mem_usage, mem_limit, net_in, net_out, blk_read, blk_write) = parse_stat(&stat);
-                let metrics = DockerMetrics {
-                    time: Timestamp::from(Utc::now()),
-                    name: stat.name.clone(),
-                    cpu_percent: cpu,
-                    mem_usage,
-                    mem_limit,
-                    net_input: net_in,
-                    net_output: net_out,
-                    block_read: blk_read,
-                    block_write: blk_write,
+        // Landing page, so a browser pointed at the port shows what it reached
+        let landing = landing_page(&config);
+        let landing_route = warp::path::end().map(move || warp::reply::html(landing.clone()));
+
+        // Filter and relabel rules from a changed config file, without losing the series
+        let reload_names = Arc::clone(&names);
+        let reload_args = args.to_vec();
+        let reload_label_map = config.label_map();
+        // Path first, so other paths are answered with 404 rather than 405
+        let reload_route = warp::path!("-" / "reload")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("authorization"))
+            .map(move |auth: Option<String>| {
+                if !authorized(reload_auth.as_deref(), auth.as_deref()) {
+                    return unauthorized();
+                }
+                let (status, body) = match reload_rules(&reload_args, &reload_label_map, &reload_names) {
+                    Ok(summary) => (warp::http::StatusCode::OK, summary),
+                    Err(e) => {
+                        warn!("Reload failed: {}", e);
+                        (warp::http::StatusCode::BAD_REQUEST, e)
+                    }
+                };
+                warp::http::Response::builder().status(status).body(format!("{}\n", body).into_bytes())
+            });
+
+        let routes = metrics_route.or(reload_route).or(health_route).or(landing_route);
+        if config.once {
+            once_registry = Some(once);
+        } else if let Some(path) = &config.unix_socket {
+            let listener = bind_unix_socket(path)?;
+            let incoming = futures::stream::unfold(listener, |listener| async {
+                Some((listener.accept().await.map(|(stream, _)| stream), listener))
+            });
+            info!("Prometheus endpoint on unix://{} ({})", path, config.metrics_path);
+            warp::serve(routes).serve_incoming_with_graceful_shutdown(incoming, shutdown_signal(deadline)).await;
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Cannot remove {}: {}", path, e);
+            }
+        } else {
+            // One server per address. With `--bind '*'` one failing is fine:
+            // `0.0.0.0` if `::` took IPv4 as well, `::` on a host without IPv6.
+            let mut servers = Vec::new();
+            for ip in config.bind_addrs() {
+                let bound = match config.tls() {
+                    Some((cert, key)) => warp::serve(routes.clone())
+                        .tls()
+                        .cert_path(cert)
+                        .key_path(key)
+                        .try_bind_with_graceful_shutdown((ip, port), shutdown_signal(deadline))
+                        .map(|(addr, server)| (format!("https://{}{}", addr, config.metrics_path), server.boxed())),
+                    None => warp::serve(routes.clone())
+                        .try_bind_with_graceful_shutdown((ip, port), shutdown_signal(deadline))
+                        .map(|(addr, server)| (format!("http://{}{}", addr, config.metrics_path), server.boxed())),
                 };
-                if let Err(e) = client.query(metrics.into_query("docker_stats")).await {
-                    eprintln!("InfluxDB write error: {}", e);
+                match bound {
+                    Ok((url, server)) => {
+                        info!("Prometheus endpoint on {}", url);
+                        servers.push(server);
+                    }
+                    Err(e) if config.bind == Bind::Any => debug!("Not listening on {}: {}", SocketAddr::new(ip, port), e),
+                    Err(e) => return Err(Error::Bind { addr: SocketAddr::new(ip, port).to_string(), reason: e.to_string() }),
                 }
             }
+            if servers.is_empty() {
+                return Err(Error::Bind { addr: format!("port {} of any address", port), reason: "all addresses are in use or unavailable".to_string() });
+            }
+            futures::future::join_all(servers).await;
         }
-    } else {
-        usage();
-        return Ok(());
+    } else if !config.once {
+        shutdown_signal(deadline).await;
+    }
+
+    // Let the pipeline finish, which flushes pending writes, then stop docker stats
+    // (the child is killed when its task is dropped). With --once the pipeline
+    // ends by itself after the snapshot.
+    if !config.once {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            info!("Stopping after --max-runtime {}s", config.max_runtime.unwrap_or_default());
+        }
+        let _ = stop_tx.send(());
+    }
+    let _ = pipeline.await;
+    if let Some(registry) = once_registry {
+        print!("{}", sink::render(&registry, false).1);
+    }
+    if let (Some(table), Some(top)) = (once_table, config.top) {
+        print!("{}", table.table(&config.sort_by, top));
+    }
+    let mut collected = true;
+    for supervisor in supervisors {
+        supervisor.abort();
+        collected &= supervisor.await.unwrap_or(true);
+    }
+    if let Some(recording) = recording {
+        let _ = recording.await;
+    }
+    if !collected {
+        return Err(Error::Collection);
     }
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn parse_decimal_guesses_the_separator() {
+        assert_eq!(parse_decimal("1.5", Locale::Auto), Some(1.5));
+        assert_eq!(parse_decimal("1,5", Locale::Auto), Some(1.5));
+        assert_eq!(parse_decimal("1,234.5", Locale::Auto), Some(1234.5));
+        assert_eq!(parse_decimal("1.234,5", Locale::Auto), Some(1234.5));
+        assert_eq!(parse_decimal("1,234,567", Locale::Auto), Some(1234567.0));
+        assert_eq!(parse_decimal("1,234", Locale::Auto), Some(1.234));
+        assert_eq!(parse_decimal("--", Locale::Auto), None);
+    }
+
+    #[test]
+    fn parse_decimal_follows_the_locale() {
+        assert_eq!(parse_decimal("1,234", Locale::Dot), Some(1234.0));
+        assert_eq!(parse_decimal("1.234", Locale::Comma), Some(1234.0));
+        assert_eq!(parse_decimal("1,5", Locale::Comma), Some(1.5));
+    }
+
+    #[test]
+    fn parse_bytes_knows_binary_and_si_units() {
+        assert_eq!(parse_bytes("0B", Locale::Auto), Some(0));
+        assert_eq!(parse_bytes("1.5GiB", Locale::Auto), Some(1610612736));
+        assert_eq!(parse_bytes("12.3kB", Locale::Auto), Some(12300));
+        assert_eq!(parse_bytes("1,5MiB", Locale::Auto), Some(1572864));
+        assert_eq!(parse_bytes(" 2MB ", Locale::Auto), Some(2000000));
+        assert_eq!(parse_bytes("42", Locale::Auto), Some(42));
+    }
+
+    #[test]
+    fn parse_bytes_rejects_garbage() {
+        assert_eq!(parse_bytes("N/A", Locale::Auto), None);
+        assert_eq!(parse_bytes("-1MiB", Locale::Auto), None);
+        assert_eq!(parse_bytes("1.5XB", Locale::Auto), None);
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.as_std().get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    fn env(cmd: &Command, key: &str) -> Option<String> {
+        cmd.as_std().get_envs()
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, value)| value.map(|v| v.to_string_lossy().into_owned()))
+    }
+
+    #[test]
+    fn docker_stats_runs_without_a_terminal() {
+        let docker = DockerTarget { binary: "docker".to_string(), ..DockerTarget::default() };
+        let cmd = stats_command(&docker, false, "{{json .}}");
+        assert_eq!(cmd.as_std().get_program(), "docker");
+        assert_eq!(args(&cmd), ["stats", "--no-trunc", "--format", "{{json .}}"]);
+        assert_eq!(env(&cmd, "TERM").as_deref(), Some("dumb"));
+        assert_eq!(env(&cmd, "DOCKER_HOST"), None);
+    }
+
+    #[test]
+    fn docker_stats_gets_the_host_and_context() {
+        let docker = DockerTarget {
+            binary: "podman".to_string(),
+            host: Some("tcp://10.0.0.5:2375".to_string()),
+            context: Some("remote".to_string()),
+            ..DockerTarget::default()
+        };
+        let cmd = stats_command(&docker, true, "{{json .}}");
+        assert_eq!(cmd.as_std().get_program(), "podman");
+        assert_eq!(args(&cmd), ["--context", "remote", "stats", "--no-stream", "--no-trunc", "--format", "{{json .}}"]);
+        assert_eq!(env(&cmd, "DOCKER_HOST").as_deref(), Some("tcp://10.0.0.5:2375"));
+    }
+
+    #[test]
+    fn docker_stats_over_ssh_is_quoted_for_the_remote_shell() {
+        let docker = DockerTarget { binary: "docker".to_string(), ssh: Some("me@box".to_string()), ..DockerTarget::default() };
+        let cmd = stats_command(&docker, false, "{{json .}}");
+        assert_eq!(cmd.as_std().get_program(), "ssh");
+        let args = args(&cmd);
+        assert_eq!(args[args.len() - 2..], ["me@box".to_string(), format!("docker stats --no-trunc --format {}", shell_quote("{{json .}}"))]);
+        assert_eq!(env(&cmd, "TERM").as_deref(), Some("dumb"));
+    }
+}
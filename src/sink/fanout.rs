@@ -0,0 +1,280 @@
+//! Several `--target`s at once, all fed by the one read loop
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::IntCounterVec;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::Sample;
+#[cfg(feature = "kafka")]
+use super::KafkaSink;
+#[cfg(feature = "remote-write")]
+use super::RemoteWriteSink;
+use super::{GraphiteSink, InfluxSink, JsonlSink, OtlpSink, PrometheusSink, PushgatewaySink, Sink, StatsdSink, TopSink};
+
+/// The sink of one target. [`Sink`] can't be used as a trait object, this
+/// is how [`Fanout`] holds sinks of different targets.
+pub(crate) enum AnySink {
+    Prometheus(PrometheusSink),
+    Influx(InfluxSink),
+    Pushgateway(PushgatewaySink),
+    #[cfg(feature = "remote-write")]
+    RemoteWrite(RemoteWriteSink),
+    Statsd(StatsdSink),
+    Otlp(OtlpSink),
+    Graphite(GraphiteSink),
+    Jsonl(JsonlSink),
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaSink),
+    Top(TopSink),
+}
+impl AnySink {
+    /// The `--target` of the sink, as the label of the dropped samples
+    fn target(&self) -> &'static str {
+        match self {
+            AnySink::Prometheus(_) => "prometheus",
+            AnySink::Influx(_) => "influxdb",
+            AnySink::Pushgateway(_) => "pushgateway",
+            #[cfg(feature = "remote-write")]
+            AnySink::RemoteWrite(_) => "remote-write",
+            AnySink::Statsd(_) => "statsd",
+            AnySink::Otlp(_) => "otlp",
+            AnySink::Graphite(_) => "graphite",
+            AnySink::Jsonl(_) => "jsonl",
+            #[cfg(feature = "kafka")]
+            AnySink::Kafka(_) => "kafka",
+            AnySink::Top(_) => "prometheus",
+        }
+    }
+}
+impl Sink for AnySink {
+    async fn record(&mut self, sample: &Sample) {
+        match self {
+            AnySink::Prometheus(sink) => sink.record(sample).await,
+            AnySink::Influx(sink) => sink.record(sample).await,
+            AnySink::Pushgateway(sink) => sink.record(sample).await,
+            #[cfg(feature = "remote-write")]
+            AnySink::RemoteWrite(sink) => sink.record(sample).await,
+            AnySink::Statsd(sink) => sink.record(sample).await,
+            AnySink::Otlp(sink) => sink.record(sample).await,
+            AnySink::Graphite(sink) => sink.record(sample).await,
+            AnySink::Jsonl(sink) => sink.record(sample).await,
+            #[cfg(feature = "kafka")]
+            AnySink::Kafka(sink) => sink.record(sample).await,
+            AnySink::Top(sink) => sink.record(sample).await,
+        }
+    }
+
+    async fn flush(&mut self) {
+        match self {
+            AnySink::Prometheus(sink) => sink.flush().await,
+            AnySink::Influx(sink) => sink.flush().await,
+            AnySink::Pushgateway(sink) => sink.flush().await,
+            #[cfg(feature = "remote-write")]
+            AnySink::RemoteWrite(sink) => sink.flush().await,
+            AnySink::Statsd(sink) => sink.flush().await,
+            AnySink::Otlp(sink) => sink.flush().await,
+            AnySink::Graphite(sink) => sink.flush().await,
+            AnySink::Jsonl(sink) => sink.flush().await,
+            #[cfg(feature = "kafka")]
+            AnySink::Kafka(sink) => sink.flush().await,
+            AnySink::Top(sink) => sink.flush().await,
+        }
+    }
+
+    async fn finish(&mut self) {
+        match self {
+            AnySink::Prometheus(sink) => sink.finish().await,
+            AnySink::Influx(sink) => sink.finish().await,
+            AnySink::Pushgateway(sink) => sink.finish().await,
+            #[cfg(feature = "remote-write")]
+            AnySink::RemoteWrite(sink) => sink.finish().await,
+            AnySink::Statsd(sink) => sink.finish().await,
+            AnySink::Otlp(sink) => sink.finish().await,
+            AnySink::Graphite(sink) => sink.finish().await,
+            AnySink::Jsonl(sink) => sink.finish().await,
+            #[cfg(feature = "kafka")]
+            AnySink::Kafka(sink) => sink.finish().await,
+            AnySink::Top(sink) => sink.finish().await,
+        }
+    }
+}
+
+/// Samples a target may have queued before [`Fanout`] drops further ones for it
+const TARGET_QUEUE: usize = 1024;
+
+/// The task of one sink of [`Fanout`] and the queue feeding it
+struct Output {
+    target: &'static str,
+    tx: mpsc::Sender<Arc<Sample>>,
+    task: JoinHandle<()>,
+    flush_every: Duration,
+}
+
+/// Every sample goes to all sinks. Each sink runs in a task of its own,
+/// recording from a queue of up to [`TARGET_QUEUE`] samples and flushing on
+/// its own interval, e.g. the Pushgateway still pushes once per `--interval`.
+/// A target that takes long to fail (InfluxDB retrying a write) so only
+/// falls behind itself: while its queue is full it misses samples, counted
+/// in `dropped` by target, and the others keep getting every sample. With a
+/// single target there is nothing to hold up, [`Sink::record`] waits for
+/// room in the queue instead of dropping.
+///
+/// [`Sink::flush`] does nothing, [`Sink::finish`] finishes all sinks at once
+/// and waits for them.
+pub(crate) struct Fanout {
+    outputs: Vec<Output>,
+    dropped: IntCounterVec,
+}
+impl Fanout {
+    /// No sinks yet, samples a target had no room for are counted in `dropped`
+    pub(crate) fn new(dropped: IntCounterVec) -> Self {
+        Fanout { outputs: Vec::new(), dropped }
+    }
+
+    /// Start the task of `sink`, flushed every `flush_every`
+    pub(crate) fn push(&mut self, sink: AnySink, flush_every: Duration) {
+        let (tx, rx) = mpsc::channel(TARGET_QUEUE);
+        let target = sink.target();
+        let task = tokio::spawn(drive(sink, rx, flush_every));
+        self.outputs.push(Output { target, tx, task, flush_every });
+    }
+
+    /// The shortest flush interval of the sinks
+    pub(crate) fn flush_every(&self) -> Duration {
+        self.outputs.iter().map(|output| output.flush_every).min().unwrap_or(Duration::from_secs(1))
+    }
+}
+impl Sink for Fanout {
+    async fn record(&mut self, sample: &Sample) {
+        let sample = Arc::new(sample.clone());
+        if let [output] = self.outputs.as_slice() {
+            // Fails only if the task panicked, which was logged
+            let _ = output.tx.send(sample).await;
+            return;
+        }
+        for output in &self.outputs {
+            if let Err(TrySendError::Full(_)) = output.tx.try_send(Arc::clone(&sample)) {
+                self.dropped.with_label_values(&[output.target]).inc();
+            }
+        }
+    }
+
+    async fn finish(&mut self) {
+        // Closing the queues first lets the sinks finish in parallel
+        let tasks: Vec<_> = self.outputs.drain(..).map(|output| output.task).collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Record the samples from `rx` to `sink` and flush it every `flush_every`,
+/// until [`Fanout`] closes the queue
+async fn drive(mut sink: AnySink, mut rx: mpsc::Receiver<Arc<Sample>>, flush_every: Duration) {
+    let mut flush_tick = tokio::time::interval(flush_every);
+    // A slow flush (a write being retried) is not made up for with a burst
+    flush_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            sample = rx.recv() => match sample {
+                Some(sample) => sink.record(&sample).await,
+                None => break,
+            },
+            _ = flush_tick.tick() => sink.flush().await,
+        }
+    }
+    sink.finish().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use prometheus::Opts;
+
+    use super::*;
+
+    fn dropped() -> IntCounterVec {
+        IntCounterVec::new(Opts::new("target_samples_dropped_total", "Dropped"), &["target"]).unwrap()
+    }
+
+    /// A jsonl sink writing to a fresh file in the temp directory
+    async fn jsonl(name: &str) -> (AnySink, PathBuf) {
+        let path = std::env::temp_dir().join(format!("dme-fanout-{}-{}.jsonl", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let sink = JsonlSink::open(path.to_str().unwrap(), 0, &[], &[]).await.unwrap();
+        (AnySink::Jsonl(sink), path)
+    }
+
+    /// Names of the samples in a jsonl file, which is removed
+    fn names(path: &Path) -> Vec<String> {
+        let contents = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        contents.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn sample(name: String) -> Sample {
+        Sample { id: name.clone(), name, ..Sample::default() }
+    }
+
+    #[tokio::test]
+    async fn every_sink_gets_every_sample() {
+        let mut fanout = Fanout::new(dropped());
+        let mut paths = Vec::new();
+        for name in ["a", "b", "c"] {
+            let (sink, path) = jsonl(name).await;
+            fanout.push(sink, Duration::from_secs(1));
+            paths.push(path);
+        }
+        let sent: Vec<String> = (0..10).map(|i| format!("web{}", i)).collect();
+        for name in &sent {
+            fanout.record(&sample(name.clone())).await;
+            tokio::task::yield_now().await;
+        }
+        fanout.finish().await;
+        for path in &paths {
+            assert_eq!(names(path), sent);
+        }
+        assert_eq!(fanout.dropped.with_label_values(&["jsonl"]).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_and_counts() {
+        let dropped = dropped();
+        let mut fanout = Fanout::new(dropped.clone());
+        let (first, first_path) = jsonl("full-a").await;
+        let (second, second_path) = jsonl("full-b").await;
+        fanout.push(first, Duration::from_secs(1));
+        fanout.push(second, Duration::from_secs(1));
+        // The sink tasks don't get to run on this single threaded runtime
+        // before the loop is done, so both queues fill up
+        for i in 0..TARGET_QUEUE + 5 {
+            fanout.record(&sample(format!("web{}", i))).await;
+        }
+        assert_eq!(dropped.with_label_values(&["jsonl"]).get(), 2 * 5);
+        fanout.finish().await;
+        assert_eq!(names(&first_path).len(), TARGET_QUEUE);
+        assert_eq!(names(&second_path).len(), TARGET_QUEUE);
+    }
+
+    #[tokio::test]
+    async fn a_single_sink_waits_instead_of_dropping() {
+        let dropped = dropped();
+        let mut fanout = Fanout::new(dropped.clone());
+        let (sink, path) = jsonl("single").await;
+        fanout.push(sink, Duration::from_secs(1));
+        for i in 0..TARGET_QUEUE + 5 {
+            fanout.record(&sample(format!("web{}", i))).await;
+        }
+        fanout.finish().await;
+        assert_eq!(names(&path).len(), TARGET_QUEUE + 5);
+        assert_eq!(dropped.with_label_values(&["jsonl"]).get(), 0);
+    }
+}
@@ -0,0 +1,137 @@
+//! Graphite plaintext protocol over TCP
+
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::Sample;
+use super::{Sink, gauge_values};
+
+/// Time allowed for connecting to the Graphite server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay before the first reconnect, doubled after every failure up to [`MAX_BACKOFF`]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Sends every sample as `docker.<labels>.<name>.<metric> <value> <timestamp>`
+/// lines, where `<labels>` are the values of `node_labels` and then of
+/// `extra_labels`. The lines of a
+/// flush period are written at once; if the connection fails they are
+/// dropped and a new connection is made with backoff on a later flush.
+pub(crate) struct GraphiteSink {
+    host: String,
+    port: u16,
+    extra_labels: Vec<String>,
+    node_labels: Vec<(String, String)>,
+    stream: Option<TcpStream>,
+    /// Lines recorded since the last flush
+    pending: String,
+    backoff: Duration,
+    next_connect: Instant,
+}
+impl GraphiteSink {
+    /// Connect to `host:port`, failing if the first connection can't be made
+    pub(crate) async fn connect(host: &str, port: u16, extra_labels: &[String], node_labels: &[(String, String)]) -> std::io::Result<Self> {
+        let stream = connect(host, port).await?;
+        Ok(GraphiteSink {
+            host: host.to_string(),
+            port,
+            extra_labels: extra_labels.to_vec(),
+            node_labels: node_labels.to_vec(),
+            stream: Some(stream),
+            pending: String::new(),
+            backoff: INITIAL_BACKOFF,
+            next_connect: Instant::now(),
+        })
+    }
+
+    /// The current connection, reconnecting if the backoff has passed
+    async fn stream(&mut self) -> Option<&mut TcpStream> {
+        if self.stream.is_none() && Instant::now() >= self.next_connect {
+            match connect(&self.host, self.port).await {
+                Ok(stream) => {
+                    info!("Reconnected to Graphite at {}:{}", self.host, self.port);
+                    self.stream = Some(stream);
+                    self.backoff = INITIAL_BACKOFF;
+                }
+                Err(e) => {
+                    warn!("Graphite connect error: {}, retrying in {:?}", e, self.backoff);
+                    self.next_connect = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        self.stream.as_mut()
+    }
+}
+
+async fn connect(host: &str, port: u16) -> std::io::Result<TcpStream> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, format!("connecting to {}:{} timed out", host, port)))?
+}
+
+/// Replace everything but letters, digits, `_` and `-` (in particular the
+/// `.` path separator and `/`) with `_`
+fn path_segment(s: &str) -> String {
+    match s {
+        "" => "_".to_string(),
+        s => s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect(),
+    }
+}
+
+/// All metrics of one sample as plaintext protocol lines
+fn graphite_lines(sample: &Sample, extra_labels: &[String], node_labels: &[(String, String)], timestamp: i64) -> String {
+    let mut path = "docker".to_string();
+    for (_, value) in node_labels {
+        path.push('.');
+        path.push_str(&path_segment(value));
+    }
+    for label in extra_labels {
+        path.push('.');
+        path.push_str(&path_segment(sample.extra_label(label)));
+    }
+    path.push('.');
+    path.push_str(&path_segment(&sample.name));
+
+    let mut lines = String::new();
+    for (metric, value) in gauge_values(sample) {
+        lines.push_str(&format!("{}.{} {} {}\n", path, metric, value, timestamp));
+    }
+    for (interface, (rx, tx)) in &sample.net_interfaces {
+        let interface = path_segment(interface);
+        lines.push_str(&format!("{}.net_interface.{}.input_bytes {} {}\n", path, interface, rx, timestamp));
+        lines.push_str(&format!("{}.net_interface.{}.output_bytes {} {}\n", path, interface, tx, timestamp));
+    }
+    for (device, (read, write)) in &sample.block_devices {
+        let device = path_segment(device);
+        lines.push_str(&format!("{}.block_device.{}.read_bytes {} {}\n", path, device, read, timestamp));
+        lines.push_str(&format!("{}.block_device.{}.write_bytes {} {}\n", path, device, write, timestamp));
+    }
+    lines
+}
+
+impl Sink for GraphiteSink {
+    async fn record(&mut self, sample: &Sample) {
+        self.pending.push_str(&graphite_lines(sample, &self.extra_labels, &self.node_labels, Utc::now().timestamp()));
+    }
+
+    async fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let lines = std::mem::take(&mut self.pending);
+        let Some(stream) = self.stream().await else {
+            warn!("Not connected to Graphite, dropping {} lines", lines.lines().count());
+            return;
+        };
+        if let Err(e) = stream.write_all(lines.as_bytes()).await {
+            warn!("Graphite write error: {}, dropping {} lines and reconnecting", e, lines.lines().count());
+            self.stream = None;
+            self.next_connect = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! InfluxDB writer, batching points into as few requests as possible
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use influxdb::{InfluxDbWriteable, Query, Timestamp, WriteQuery};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::{CollectorStats, Sample};
+
+use super::{RateTracker, Sink, with_retries};
+
+/// Measurement for the exporter's own counters
+const EXPORTER_MEASUREMENT: &str = "docker_exporter";
+
+#[derive(InfluxDbWriteable)]
+struct DockerMetrics {
+    time: Timestamp,
+    #[influxdb(tag)] name: String,
+    #[influxdb(tag)] id: String,
+    cpu_percent: Option<f64>,
+    cpu_cores: Option<f64>,
+    mem_usage: Option<u64>,
+    mem_limit: Option<u64>,
+    mem_limited: Option<bool>,
+    mem_percent: Option<f64>,
+    mem_cache: Option<u64>,
+    mem_rss: Option<u64>,
+    mem_swap: Option<u64>,
+    net_input: Option<u64>,
+    net_output: Option<u64>,
+    block_read: Option<u64>,
+    block_write: Option<u64>,
+    pids: Option<u64>,
+    cpu_throttled_periods_total: Option<u64>,
+    cpu_throttled_seconds_total: Option<f64>,
+    net_input_per_sec: Option<f64>,
+    net_output_per_sec: Option<f64>,
+    block_read_per_sec: Option<f64>,
+    block_write_per_sec: Option<f64>,
+    uptime_seconds: Option<f64>,
+    running: Option<bool>,
+    restart_count: Option<u64>,
+    oom_killed: Option<bool>,
+    health_status: Option<String>,
+    health_failing_streak: Option<u64>,
+}
+
+/// Precision of the point timestamps, `--influx-precision`. Points of the
+/// same series within one unit overwrite each other.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InfluxPrecision {
+    Ms,
+    Us,
+    #[default]
+    Ns,
+}
+impl std::str::FromStr for InfluxPrecision {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "ms" => Ok(InfluxPrecision::Ms),
+            "us" => Ok(InfluxPrecision::Us),
+            "ns" => Ok(InfluxPrecision::Ns),
+            _ => Err(()),
+        }
+    }
+}
+impl InfluxPrecision {
+    /// The current time in this precision
+    fn now(self) -> Timestamp {
+        self.at(Utc::now())
+    }
+
+    fn at(self, time: DateTime<Utc>) -> Timestamp {
+        match self {
+            InfluxPrecision::Ms => Timestamp::Milliseconds(time.timestamp_millis() as u128),
+            InfluxPrecision::Us => Timestamp::Microseconds(time.timestamp_micros() as u128),
+            InfluxPrecision::Ns => Timestamp::from(time),
+        }
+    }
+
+    /// Value of the `precision` query parameter, which 1.x and 2.x spell differently for µs
+    fn param(self, v2: bool) -> String {
+        match self {
+            InfluxPrecision::Ms => "ms",
+            InfluxPrecision::Us if v2 => "us",
+            InfluxPrecision::Us => "u",
+            InfluxPrecision::Ns => "ns",
+        }.to_string()
+    }
+}
+
+/// What the timestamps of the container points are, `--influx-timestamp`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InfluxTimestamp {
+    /// When the sample was read from docker
+    #[default]
+    Read,
+    /// When the sink got the sample, after any time it spent queued
+    Write,
+}
+impl std::str::FromStr for InfluxTimestamp {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "read" => Ok(InfluxTimestamp::Read),
+            "write" => Ok(InfluxTimestamp::Write),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Write API of the InfluxDB server. Requests are made directly rather than
+/// with `influxdb::Client`, which reports server errors without a JSON body
+/// (e.g. a bare 500) as success.
+pub(crate) struct InfluxApi {
+    http: reqwest::Client,
+    url: String,
+    /// Target database or bucket, credentials and precision
+    params: Vec<(&'static str, String)>,
+    /// `Authorization` header
+    token: Option<String>,
+    /// Line protocol escaping of 2.x
+    v2: bool,
+    precision: InfluxPrecision,
+    /// Send the body gzip-compressed
+    compress: bool,
+}
+impl InfluxApi {
+    /// `/write` of InfluxDB 1.x (or the 1.x compatibility API of 2.x),
+    /// authenticated with a token or username and password if given, sent
+    /// with an [`InfluxApi::http_client`]
+    pub(crate) fn v1(http: reqwest::Client, base_url: &str, db: &str, token: Option<&str>, user: Option<(&str, &str)>, precision: InfluxPrecision) -> Self {
+        let mut params = vec![("db", db.to_string())];
+        if let Some((user, password)) = user {
+            params.push(("u", user.to_string()));
+            params.push(("p", password.to_string()));
+        }
+        params.push(("precision", precision.param(false)));
+        InfluxApi {
+            http,
+            url: format!("{}/write", base_url),
+            params,
+            token: token.map(str::to_string),
+            v2: false,
+            precision,
+            compress: false,
+        }
+    }
+
+    /// `/api/v2/write` of InfluxDB 2.x, authenticated with an API token
+    pub(crate) fn v2(http: reqwest::Client, base_url: &str, org: &str, bucket: &str, token: &str, precision: InfluxPrecision) -> Self {
+        InfluxApi {
+            http,
+            url: format!("{}/api/v2/write", base_url),
+            params: vec![("org", org.to_string()), ("bucket", bucket.to_string()), ("precision", precision.param(true))],
+            token: Some(token.to_string()),
+            v2: true,
+            precision,
+            compress: false,
+        }
+    }
+
+    /// Gzip the body of every write (`--influx-compress`), line protocol
+    /// compresses to a fraction of its size
+    pub(crate) fn compressed(self, compress: bool) -> Self {
+        InfluxApi { compress, ..self }
+    }
+
+    /// Client with the timeouts of the HTTP targets, trusting the system roots
+    /// and the PEM certificate(s) in `ca_cert`, or any certificate at all if `insecure`
+    pub(crate) fn http_client(ca_cert: Option<&str>, insecure: bool) -> Result<reqwest::Client, String> {
+        let mut builder = super::http_client().danger_accept_invalid_certs(insecure);
+        if let Some(path) = ca_cert {
+            let pem = std::fs::read(path).map_err(|e| format!("Cannot read --influx-ca-cert {}: {}", path, e))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .ok().filter(|certs| !certs.is_empty())
+                .ok_or_else(|| format!("No PEM certificate in --influx-ca-cert {}", path))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        builder.build().map_err(|e| format!("Cannot set up the InfluxDB client: {}", e))
+    }
+}
+
+/// `value` as a tag value. The influxdb crate escapes commas, spaces and `=`,
+/// but line protocol has no escape for line breaks, which would split the
+/// point and fail the whole batch; they and other control characters become
+/// spaces.
+fn tag_value(value: &str) -> String {
+    value.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+/// Rate state of containers without a sample for this long is dropped
+const RATE_STATE_TTL: Duration = Duration::from_secs(600);
+
+/// Batches given up in a row before [`CircuitBreaker`] opens
+const BREAKER_FAILURES: u32 = 3;
+
+/// How long [`CircuitBreaker`] stays open before a write is tried again
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Where [`CircuitBreaker`] stands for the next write
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BreakerState {
+    /// Writes go out as usual, with retries
+    Closed,
+    /// Writes are given up without trying, InfluxDB kept failing
+    Open,
+    /// The cooldown is over, one write without retries tells whether InfluxDB is back
+    HalfOpen,
+}
+
+/// Stops writing to an InfluxDB that is down, so that the read loop doesn't
+/// spend the retries and connect timeouts of every batch waiting for it.
+/// It opens after [`BREAKER_FAILURES`] batches in a row were given up and
+/// half-opens after [`BREAKER_COOLDOWN`]; the write tried then closes it
+/// again or opens it for another cooldown.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+impl CircuitBreaker {
+    fn state(&self) -> BreakerState {
+        match self.open_until {
+            None => BreakerState::Closed,
+            Some(until) if Instant::now() < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    fn success(&mut self) {
+        *self = CircuitBreaker::default();
+    }
+
+    /// Count a given up batch, returns whether the breaker (re)opened
+    fn failure(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures < BREAKER_FAILURES {
+            return false;
+        }
+        self.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        true
+    }
+}
+
+/// Collects points and writes them in batches of up to `batch_size`, or
+/// whatever is pending when flushed. Each written batch also carries the
+/// current collector error counts and the number of containers with a
+/// sample within `stale_timeout`. Container points go to `measurement`,
+/// timestamped as chosen by `timestamp`, `extra_labels` are written as
+/// additional tags. The fields in `disabled` are left out.
+///
+/// A failed write is retried with backoff. Batches that still fail are
+/// counted and appended as line protocol to `dead_letter`, if set, as are
+/// the batches [`CircuitBreaker`] doesn't let through.
+pub(crate) struct InfluxSink {
+    api: InfluxApi,
+    batch: Vec<WriteQuery>,
+    batch_size: usize,
+    measurement: String,
+    stats: CollectorStats,
+    extra_labels: Vec<String>,
+    /// `--node-label` tags of every point
+    node_labels: Vec<(String, String)>,
+    rates: RateTracker,
+    dead_letter: Option<PathBuf>,
+    /// `--disable-metric` field names
+    disabled: Vec<String>,
+    stale_timeout: Duration,
+    timestamp: InfluxTimestamp,
+    breaker: CircuitBreaker,
+}
+impl InfluxSink {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        api: InfluxApi,
+        batch_size: usize,
+        measurement: &str,
+        stats: CollectorStats,
+        extra_labels: &[String],
+        node_labels: &[(String, String)],
+        dead_letter: Option<&str>,
+        disabled: &[String],
+        stale_timeout: Duration,
+        timestamp: InfluxTimestamp,
+    ) -> Self {
+        InfluxSink {
+            api,
+            batch: Vec::with_capacity(batch_size),
+            batch_size,
+            measurement: measurement.to_string(),
+            stats,
+            extra_labels: extra_labels.to_vec(),
+            node_labels: node_labels.to_vec(),
+            rates: RateTracker::default(),
+            dead_letter: dead_letter.map(PathBuf::from),
+            disabled: disabled.to_vec(),
+            stale_timeout,
+            timestamp,
+            breaker: CircuitBreaker::default(),
+        }
+    }
+
+    /// `value` unless `field` is disabled
+    fn field<T>(&self, field: &str, value: T) -> Option<T> {
+        Some(value).filter(|_| !self.disabled.iter().any(|d| d == field))
+    }
+
+    fn add_extra_tags(&self, mut query: WriteQuery, sample: &Sample) -> WriteQuery {
+        for label in &self.extra_labels {
+            // Line protocol can't carry empty tag values
+            let value = sample.extra_label(label);
+            if !value.is_empty() {
+                query = query.add_tag(label.as_str(), tag_value(value));
+            }
+        }
+        self.add_node_tags(query)
+    }
+
+    fn add_node_tags(&self, mut query: WriteQuery) -> WriteQuery {
+        for (key, value) in &self.node_labels {
+            query = query.add_tag(key.as_str(), value.clone());
+        }
+        query
+    }
+
+    /// Append the pending batch to the dead letter file, escaped for the
+    /// API in use so it can be replayed as is
+    async fn write_dead_letter(&self, path: &Path) -> std::io::Result<()> {
+        let lines = self.batch.build_with_opts(self.api.v2)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .get();
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        file.write_all(format!("{}\n", lines).as_bytes()).await?;
+        file.sync_data().await
+    }
+
+    /// Send the pending batch, line protocol escaping depends on the API version
+    async fn write(&self) -> Result<(), String> {
+        let body = self.batch.build_with_opts(self.api.v2).map_err(|e| e.to_string())?.get();
+        let mut req = self.api.http.post(&self.api.url)
+            .query(&self.api.params)
+            .header("Content-Type", "text/plain; charset=utf-8");
+        req = match self.api.compress {
+            true => req.header("Content-Encoding", "gzip").body(crate::gzip(body.as_bytes())),
+            false => req.body(body),
+        };
+        if let Some(token) = &self.api.token {
+            req = req.header("Authorization", format!("Token {}", token));
+        }
+        let res = req.send().await.map_err(|e| e.to_string())?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = res.text().await.unwrap_or_default();
+        match body.trim() {
+            "" => Err(status.to_string()),
+            body => Err(format!("{}: {}", status, body)),
+        }
+    }
+}
+impl Sink for InfluxSink {
+    async fn record(&mut self, sample: &Sample) {
+        let rates = self.rates.update(sample);
+        let time = match (self.timestamp, sample.read_at) {
+            (InfluxTimestamp::Read, Some(read_at)) => self.api.precision.at(read_at),
+            _ => self.api.precision.now(),
+        };
+        let metrics = DockerMetrics {
+            time,
+            name: tag_value(&sample.name),
+            id: tag_value(&sample.id),
+            cpu_percent: self.field("cpu_percent", sample.cpu),
+            cpu_cores: self.field("cpu_cores", sample.cpu_cores).flatten(),
+            mem_usage: self.field("mem_usage", sample.mem_usage),
+            mem_limit: self.field("mem_limit", sample.mem_limit).flatten(),
+            mem_limited: self.field("mem_limited", sample.mem_limit.is_some()),
+            mem_percent: self.field("mem_percent", sample.mem_percent).flatten(),
+            mem_cache: self.field("mem_cache", sample.mem_cache).flatten(),
+            mem_rss: self.field("mem_rss", sample.mem_rss).flatten(),
+            mem_swap: self.field("mem_swap", sample.mem_swap).flatten(),
+            net_input: self.field("net_input", sample.net_in),
+            net_output: self.field("net_output", sample.net_out),
+            block_read: self.field("block_read", sample.block_read),
+            block_write: self.field("block_write", sample.block_write),
+            pids: self.field("pids", sample.pids),
+            cpu_throttled_periods_total: self.field("cpu_throttled", sample.cpu_throttled_periods).flatten(),
+            cpu_throttled_seconds_total: self.field("cpu_throttled", sample.cpu_throttled_seconds).flatten(),
+            net_input_per_sec: self.field("net_input", rates.map(|r| r.net_in)).flatten(),
+            net_output_per_sec: self.field("net_output", rates.map(|r| r.net_out)).flatten(),
+            block_read_per_sec: self.field("block_read", rates.map(|r| r.block_read)).flatten(),
+            block_write_per_sec: self.field("block_write", rates.map(|r| r.block_write)).flatten(),
+            uptime_seconds: self.field("uptime_seconds", sample.uptime()).flatten(),
+            running: self.field("running", !sample.stopped),
+            restart_count: self.field("restart_count", sample.restart_count).flatten(),
+            oom_killed: self.field("oom_killed", sample.oom_killed).flatten(),
+            health_status: self.field("health", sample.health_status.clone()).flatten(),
+            health_failing_streak: self.field("health", sample.health_failing_streak).flatten(),
+        };
+        let time = metrics.time;
+        let query = metrics.into_query(self.measurement.as_str());
+        self.batch.push(self.add_extra_tags(query, sample));
+        // API backend only: one point per interface and device next to the totals
+        for (interface, &(rx, tx)) in &sample.net_interfaces {
+            let (rx, tx) = (self.field("net_input", rx), self.field("net_output", tx));
+            if rx.is_none() && tx.is_none() {
+                break;
+            }
+            let mut query = time.into_query(format!("{}_net", self.measurement))
+                .add_tag("name", tag_value(&sample.name))
+                .add_tag("id", tag_value(&sample.id))
+                .add_tag("interface", tag_value(interface));
+            if let Some(rx) = rx {
+                query = query.add_field("net_input", rx);
+            }
+            if let Some(tx) = tx {
+                query = query.add_field("net_output", tx);
+            }
+            self.batch.push(self.add_extra_tags(query, sample));
+        }
+        for (device, &(read, write)) in &sample.block_devices {
+            let (read, write) = (self.field("block_read", read), self.field("block_write", write));
+            if read.is_none() && write.is_none() {
+                break;
+            }
+            let mut query = time.into_query(format!("{}_blkio", self.measurement))
+                .add_tag("name", tag_value(&sample.name))
+                .add_tag("id", tag_value(&sample.id))
+                .add_tag("device", tag_value(device));
+            if let Some(read) = read {
+                query = query.add_field("block_read", read);
+            }
+            if let Some(write) = write {
+                query = query.add_field("block_write", write);
+            }
+            self.batch.push(self.add_extra_tags(query, sample));
+        }
+        if self.batch.len() >= self.batch_size {
+            self.flush().await;
+        }
+    }
+
+    /// Write all pending points in one request, see [`InfluxSink`] for failures
+    async fn flush(&mut self) {
+        self.rates.evict_older_than(RATE_STATE_TTL);
+        if self.batch.is_empty() {
+            return;
+        }
+        let exporter = self.api.precision.now()
+            .into_query(EXPORTER_MEASUREMENT)
+            .add_field("parse_errors_total", self.stats.parse_errors.get())
+            .add_field("scrape_errors_total", self.stats.scrape_errors.get())
+            .add_field("stats_warming_up_total", self.stats.warming_up.get())
+            .add_field("cpu_clamped_total", self.stats.cpu_clamped.get())
+            .add_field("influx_write_failures_total", self.stats.influx_write_failures.get())
+            .add_field("influx_circuit_open_total", self.stats.influx_circuit_open.get())
+            .add_field("write_queue_depth", self.stats.write_queue_depth.get())
+            .add_field("containers_total", self.rates.count_within(self.stale_timeout) as u64);
+        self.batch.push(self.add_node_tags(exporter));
+        let state = self.breaker.state();
+        let written = match state {
+            BreakerState::Closed => with_retries("InfluxDB write", || self.write()).await,
+            BreakerState::HalfOpen => self.write().await,
+            BreakerState::Open => {
+                self.stats.influx_circuit_open.inc();
+                match &self.dead_letter {
+                    Some(path) => match self.write_dead_letter(path).await {
+                        Ok(()) => debug!("InfluxDB circuit open, saved {} points to {}", self.batch.len(), path.display()),
+                        Err(dl) => error!("InfluxDB circuit open, dropping {} points (dead letter file: {})", self.batch.len(), dl),
+                    },
+                    None => debug!("InfluxDB circuit open, dropping {} points", self.batch.len()),
+                }
+                self.batch.clear();
+                return;
+            }
+        };
+        match written {
+            Ok(()) => {
+                if state == BreakerState::HalfOpen {
+                    info!("InfluxDB writes work again, closing the circuit");
+                }
+                self.breaker.success();
+            }
+            Err(e) => {
+                self.stats.influx_write_failures.inc();
+                match &self.dead_letter {
+                    Some(path) => match self.write_dead_letter(path).await {
+                        Ok(()) => warn!("InfluxDB write error: {}, saved {} points to {}", e, self.batch.len(), path.display()),
+                        Err(dl) => error!("InfluxDB write error: {}, dropping {} points (dead letter file: {})", e, self.batch.len(), dl),
+                    },
+                    None => error!("InfluxDB write error: {}, dropping {} points", e, self.batch.len()),
+                }
+                if self.breaker.failure() {
+                    warn!("InfluxDB circuit open after {} failed batches, not writing for {:?}", self.breaker.failures, BREAKER_COOLDOWN);
+                }
+            }
+        }
+        self.batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker_opens_after_repeated_failures() {
+        let mut breaker = CircuitBreaker::default();
+        for _ in 1..BREAKER_FAILURES {
+            assert!(!breaker.failure());
+            assert_eq!(breaker.state(), BreakerState::Closed);
+        }
+        assert!(breaker.failure());
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn breaker_half_opens_after_the_cooldown() {
+        let mut breaker = CircuitBreaker { failures: BREAKER_FAILURES, open_until: Some(Instant::now()) };
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        // The trial write failed: open for another cooldown
+        assert!(breaker.failure());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        breaker.open_until = Some(Instant::now());
+        breaker.success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(!breaker.failure());
+    }
+}
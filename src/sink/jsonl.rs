@@ -0,0 +1,205 @@
+//! JSON lines file (or stdout) writer for offline analysis
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::Sample;
+use super::Sink;
+
+/// One line of output
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    name: &'a str,
+    id: &'a str,
+    cpu_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_cores: Option<f64>,
+    mem_usage: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_limit: Option<u64>,
+    mem_limited: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_cache: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_rss: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mem_swap: Option<u64>,
+    net_input: u64,
+    net_output: u64,
+    block_read: u64,
+    block_write: u64,
+    pids: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_throttled_periods_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_throttled_seconds_total: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uptime_seconds: Option<f64>,
+    running: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restart_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oom_killed: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_failing_streak: Option<u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    net_interfaces: BTreeMap<&'a str, NetIo>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    block_devices: BTreeMap<&'a str, BlockIo>,
+    #[serde(flatten)]
+    labels: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Serialize)]
+struct NetIo {
+    input: u64,
+    output: u64,
+}
+
+#[derive(Serialize)]
+struct BlockIo {
+    read: u64,
+    write: u64,
+}
+
+enum Output {
+    Stdout(BufWriter<tokio::io::Stdout>),
+    File { path: PathBuf, file: BufWriter<File>, size: u64 },
+}
+
+/// Appends one JSON object per sample to a file, or stdout for `-`. The
+/// file is synced to disk on every flush and rotated (renamed with a
+/// timestamp suffix) once it would grow beyond `max_file_bytes`.
+pub(crate) struct JsonlSink {
+    output: Output,
+    /// No rotation if 0
+    max_file_bytes: u64,
+    extra_labels: Vec<String>,
+    /// Written as additional keys, like `extra_labels`
+    node_labels: Vec<(String, String)>,
+}
+impl JsonlSink {
+    pub(crate) async fn open(path: &str, max_file_bytes: u64, extra_labels: &[String], node_labels: &[(String, String)]) -> std::io::Result<Self> {
+        let output = if path == "-" {
+            Output::Stdout(BufWriter::new(tokio::io::stdout()))
+        } else {
+            let path = PathBuf::from(path);
+            let (file, size) = open_append(&path).await?;
+            Output::File { path, file, size }
+        };
+        Ok(JsonlSink { output, max_file_bytes, extra_labels: extra_labels.to_vec(), node_labels: node_labels.to_vec() })
+    }
+
+    /// Move the current file aside and continue in a fresh one
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        let Output::File { path, file, size } = &mut self.output else { return Ok(()) };
+        file.flush().await?;
+        file.get_ref().sync_all().await?;
+        let rotated = PathBuf::from(format!("{}.{}", path.display(), Utc::now().format("%Y%m%dT%H%M%S%.3f")));
+        tokio::fs::rename(&*path, &rotated).await?;
+        info!("Rotated {} to {}", path.display(), rotated.display());
+        (*file, *size) = open_append(path).await?;
+        Ok(())
+    }
+
+    async fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        if let Output::File { size, .. } = &self.output
+            && self.max_file_bytes > 0
+            && *size > 0
+            && *size + line.len() as u64 > self.max_file_bytes
+        {
+            self.rotate().await?;
+        }
+        match &mut self.output {
+            Output::Stdout(out) => out.write_all(line).await,
+            Output::File { file, size, .. } => {
+                file.write_all(line).await?;
+                *size += line.len() as u64;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn open_append(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path).await?;
+    let size = file.metadata().await?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+/// `sample` as one JSON object without a line break, the `extra_labels` and
+/// `node_labels` as additional keys
+pub(super) fn to_json(sample: &Sample, extra_labels: &[String], node_labels: &[(String, String)]) -> Vec<u8> {
+    let record = Record {
+        timestamp: Utc::now(),
+        name: &sample.name,
+        id: &sample.id,
+        cpu_percent: sample.cpu,
+        cpu_cores: sample.cpu_cores,
+        mem_usage: sample.mem_usage,
+        mem_limit: sample.mem_limit,
+        mem_limited: sample.mem_limit.is_some(),
+        mem_percent: sample.mem_percent,
+        mem_cache: sample.mem_cache,
+        mem_rss: sample.mem_rss,
+        mem_swap: sample.mem_swap,
+        net_input: sample.net_in,
+        net_output: sample.net_out,
+        block_read: sample.block_read,
+        block_write: sample.block_write,
+        pids: sample.pids,
+        cpu_throttled_periods_total: sample.cpu_throttled_periods,
+        cpu_throttled_seconds_total: sample.cpu_throttled_seconds,
+        uptime_seconds: sample.uptime(),
+        running: !sample.stopped,
+        restart_count: sample.restart_count,
+        oom_killed: sample.oom_killed,
+        health_status: sample.health_status.as_deref(),
+        health_failing_streak: sample.health_failing_streak,
+        net_interfaces: sample.net_interfaces.iter()
+            .map(|(interface, &(input, output))| (interface.as_str(), NetIo { input, output }))
+            .collect(),
+        block_devices: sample.block_devices.iter()
+            .map(|(device, &(read, write))| (device.as_str(), BlockIo { read, write }))
+            .collect(),
+        labels: extra_labels.iter().map(|l| (l.as_str(), sample.extra_label(l)))
+            .chain(node_labels.iter().map(|(key, value)| (key.as_str(), value.as_str())))
+            .collect(),
+    };
+    serde_json::to_vec(&record).unwrap()
+}
+
+impl Sink for JsonlSink {
+    async fn record(&mut self, sample: &Sample) {
+        let mut line = to_json(sample, &self.extra_labels, &self.node_labels);
+        line.push(b'\n');
+        if let Err(e) = self.write_line(&line).await {
+            warn!("JSON lines write error: {}", e);
+        }
+    }
+
+    /// Write out buffered lines and sync the file to disk
+    async fn flush(&mut self) {
+        let result = match &mut self.output {
+            Output::Stdout(out) => out.flush().await,
+            Output::File { file, .. } => match file.flush().await {
+                Ok(()) => file.get_ref().sync_data().await,
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            warn!("JSON lines flush error: {}", e);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Kafka producer for event-streaming pipelines, built with the `kafka` feature
+
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use prometheus::IntCounter;
+use rdkafka::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+
+use crate::Sample;
+use super::Sink;
+use super::jsonl::to_json;
+
+/// How long librdkafka tries to deliver a message before it counts as failed
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the messages still queued on shutdown may take to be delivered
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counts the messages librdkafka gave up on, called from its polling thread
+struct DeliveryReports {
+    errors: IntCounter,
+}
+impl ClientContext for DeliveryReports {}
+impl ProducerContext for DeliveryReports {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((e, _)) = result {
+            debug!("Kafka delivery failed: {}", e);
+            self.errors.inc();
+        }
+    }
+}
+
+/// Publishes every sample as a JSON object (the one `--target jsonl` writes)
+/// to `topic`, keyed by the container name so all messages of a container go
+/// to the same partition. librdkafka batches and retries in the background;
+/// messages that could not be queued or delivered within [`MESSAGE_TIMEOUT`]
+/// are counted in `errors`, and logged on the next flush.
+pub(crate) struct KafkaSink {
+    producer: ThreadedProducer<DeliveryReports>,
+    topic: String,
+    extra_labels: Vec<String>,
+    /// Written as additional keys, like `extra_labels`
+    node_labels: Vec<(String, String)>,
+    errors: IntCounter,
+    /// Value of `errors` at the last flush
+    reported: u64,
+}
+impl KafkaSink {
+    /// Producer for the comma separated `brokers`, the connection is made in the background
+    pub(crate) fn new(
+        brokers: &str,
+        topic: &str,
+        extra_labels: &[String],
+        node_labels: &[(String, String)],
+        errors: IntCounter,
+    ) -> Result<Self, String> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", MESSAGE_TIMEOUT.as_millis().to_string())
+            .create_with_context(DeliveryReports { errors: errors.clone() })
+            .map_err(|e| format!("{}: {}", brokers, e))?;
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            extra_labels: extra_labels.to_vec(),
+            node_labels: node_labels.to_vec(),
+            reported: errors.get(),
+            errors,
+        })
+    }
+
+    /// Log the failed messages since the last call, at most once per flush
+    fn report_errors(&mut self) {
+        let errors = self.errors.get();
+        if errors > self.reported {
+            warn!("{} Kafka messages could not be delivered to {}", errors - self.reported, self.topic);
+            self.reported = errors;
+        }
+    }
+}
+impl Sink for KafkaSink {
+    async fn record(&mut self, sample: &Sample) {
+        let payload = to_json(sample, &self.extra_labels, &self.node_labels);
+        let record = BaseRecord::to(&self.topic).key(&sample.name).payload(&payload);
+        // Only fails right away if the local queue is full or the topic is invalid
+        if let Err((e, _)) = self.producer.send(record) {
+            debug!("Kafka message for {} not queued: {}", sample.name, e);
+            self.errors.inc();
+        }
+    }
+
+    async fn flush(&mut self) {
+        self.report_errors();
+    }
+
+    /// Wait up to [`FINISH_TIMEOUT`] for the queued messages to be delivered
+    async fn finish(&mut self) {
+        let producer = self.producer.clone();
+        let queued = producer.in_flight_count();
+        if queued == 0 {
+            self.report_errors();
+            return;
+        }
+        match tokio::task::spawn_blocking(move || producer.flush(FINISH_TIMEOUT)).await {
+            Ok(Ok(())) => info!("Delivered the {} queued Kafka messages", queued),
+            Ok(Err(e)) => warn!("Kafka messages still queued on shutdown are lost: {}", e),
+            Err(e) => warn!("Kafka flush failed: {}", e),
+        }
+        self.report_errors();
+    }
+}
+
+/// `--check`: whether the `brokers` answer within `timeout` and have partitions for `topic`
+pub(crate) async fn check_brokers(brokers: &str, topic: &str, timeout: Duration) -> Result<String, String> {
+    let producer: BaseProducer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()
+        .map_err(|e| format!("{}: {}", brokers, e))?;
+    let name = topic.to_string();
+    let metadata = tokio::task::spawn_blocking(move || producer.client().fetch_metadata(Some(&name), timeout))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{}: {}", brokers, e))?;
+    let partitions = metadata.topics().iter().map(|topic| topic.partitions().len()).sum::<usize>();
+    match partitions {
+        0 => Err(format!("{} answered, but topic {} has no partitions", brokers, topic)),
+        n => Ok(format!("{} brokers answered, topic {} has {} partitions", metadata.brokers().len(), topic, n)),
+    }
+}
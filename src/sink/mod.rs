@@ -0,0 +1,210 @@
+//! Export targets. Every target implements [`Sink`] and is driven by the
+//! same read loop in `main`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::Sample;
+
+mod fanout;
+mod graphite;
+mod influx;
+mod jsonl;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod otlp;
+mod prometheus;
+mod pushgateway;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+mod statsd;
+mod top;
+
+pub(crate) use fanout::{AnySink, Fanout};
+pub(crate) use graphite::GraphiteSink;
+pub(crate) use influx::{InfluxApi, InfluxPrecision, InfluxSink, InfluxTimestamp};
+pub(crate) use jsonl::JsonlSink;
+#[cfg(feature = "kafka")]
+pub(crate) use kafka::{KafkaSink, check_brokers};
+pub(crate) use otlp::OtlpSink;
+pub(crate) use prometheus::{Metrics, PrometheusSink, render};
+pub(crate) use pushgateway::PushgatewaySink;
+#[cfg(feature = "remote-write")]
+pub(crate) use remote_write::RemoteWriteSink;
+pub(crate) use statsd::StatsdSink;
+pub(crate) use top::TopSink;
+
+/// Tries per write before it is given up
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long the HTTP targets wait for a connection
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a whole HTTP write may take, so a server that stops answering
+/// fails the write (and is retried) instead of stalling the pipeline
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client builder for the HTTP targets, with [`HTTP_CONNECT_TIMEOUT`] and [`HTTP_TIMEOUT`]
+fn http_client() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+}
+
+/// Run `write` until it succeeds, at most [`WRITE_ATTEMPTS`] times with
+/// exponential backoff in between. Returns the last error.
+async fn with_retries<F, Fut>(what: &str, mut write: F) -> Result<(), String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let mut delay = RETRY_DELAY;
+    for _ in 1..WRITE_ATTEMPTS {
+        match write().await {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("{} error: {}, retrying in {:?}", what, e, delay),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    write().await
+}
+
+/// Name and value of every gauge of a sample, for the targets without
+/// fields or labels of their own (StatsD, Graphite). Unknown values are left out.
+fn gauge_values(sample: &Sample) -> Vec<(&'static str, f64)> {
+    let mut gauges = vec![
+        ("cpu_percent", sample.cpu),
+        ("mem_usage_bytes", sample.mem_usage as f64),
+        ("mem_limited", if sample.mem_limit.is_some() { 1.0 } else { 0.0 }),
+        ("net_input_bytes", sample.net_in as f64),
+        ("net_output_bytes", sample.net_out as f64),
+        ("block_read_bytes", sample.block_read as f64),
+        ("block_write_bytes", sample.block_write as f64),
+        ("pids", sample.pids as f64),
+        ("container_running", if sample.stopped { 0.0 } else { 1.0 }),
+    ];
+    gauges.extend(sample.cpu_cores.map(|cores| ("cpu_cores", cores)));
+    gauges.extend(sample.mem_limit.map(|limit| ("mem_limit_bytes", limit as f64)));
+    gauges.extend(sample.mem_percent.map(|percent| ("mem_percent", percent)));
+    gauges.extend(sample.mem_cache.map(|bytes| ("mem_cache_bytes", bytes as f64)));
+    gauges.extend(sample.mem_rss.map(|bytes| ("mem_rss_bytes", bytes as f64)));
+    gauges.extend(sample.mem_swap.map(|bytes| ("mem_swap_bytes", bytes as f64)));
+    gauges.extend(sample.uptime().map(|secs| ("uptime_seconds", secs)));
+    gauges.extend(sample.restart_count.map(|restarts| ("restart_count", restarts as f64)));
+    gauges.extend(sample.oom_killed.map(|oom_killed| ("oom_killed", if oom_killed { 1.0 } else { 0.0 })));
+    gauges.extend(sample.health_failing_streak.map(|streak| ("health_failing_streak", streak as f64)));
+    gauges.extend(sample.cpu_throttled_periods.map(|periods| ("cpu_throttled_periods_total", periods as f64)));
+    gauges.extend(sample.cpu_throttled_seconds.map(|secs| ("cpu_throttled_seconds_total", secs)));
+    gauges
+}
+
+/// Per-second rates derived from the cumulative I/O counters of two consecutive samples
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct IoRates {
+    pub(crate) net_in: f64,
+    pub(crate) net_out: f64,
+    pub(crate) block_read: f64,
+    pub(crate) block_write: f64,
+}
+
+/// Remembers the previous I/O counters of every container to derive [`IoRates`]
+#[derive(Default)]
+pub(crate) struct RateTracker {
+    prev: HashMap<(String, String), (Instant, [u64; 4])>,
+}
+impl RateTracker {
+    /// Rates since the previous sample of the same container, `None` for the first one.
+    /// A counter that went down (container restarted) yields 0 for that interval.
+    pub(crate) fn update(&mut self, sample: &Sample) -> Option<IoRates> {
+        let now = Instant::now();
+        let counters = [sample.net_in, sample.net_out, sample.block_read, sample.block_write];
+        let key = (sample.name.clone(), sample.id.clone());
+        let (then, prev) = self.prev.insert(key, (now, counters))?;
+        let secs = now.duration_since(then).as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        let rate = |i: usize| counters[i].checked_sub(prev[i]).map_or(0.0, |delta| delta as f64 / secs);
+        Some(IoRates { net_in: rate(0), net_out: rate(1), block_read: rate(2), block_write: rate(3) })
+    }
+
+    /// Forget a container
+    pub(crate) fn remove(&mut self, name: &str, id: &str) {
+        self.prev.remove(&(name.to_string(), id.to_string()));
+    }
+
+    /// Containers with a sample within `timeout`
+    pub(crate) fn count_within(&self, timeout: Duration) -> usize {
+        self.prev.values().filter(|(seen, _)| seen.elapsed() <= timeout).count()
+    }
+
+    /// Forget all containers without a sample within `timeout`
+    pub(crate) fn evict_older_than(&mut self, timeout: Duration) {
+        self.prev.retain(|_, (seen, _)| seen.elapsed() <= timeout);
+    }
+}
+
+/// Destination for collected samples
+pub(crate) trait Sink: Send {
+    /// Handle one sample
+    fn record(&mut self, sample: &Sample) -> impl Future<Output = ()> + Send;
+
+    /// Push out anything buffered, called periodically
+    fn flush(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Push out everything left when the pipeline stops, a last flush by default
+    fn finish(&mut self) -> impl Future<Output = ()> + Send {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(net_in: u64, block_write: u64) -> Sample {
+        Sample { name: "web".to_string(), id: "abc123".to_string(), net_in, block_write, ..Sample::default() }
+    }
+
+    #[test]
+    fn rates_need_two_samples() {
+        let mut rates = RateTracker::default();
+        assert!(rates.update(&sample(1000, 0)).is_none());
+        std::thread::sleep(Duration::from_millis(50));
+        let rate = rates.update(&sample(2000, 500)).unwrap();
+        assert!(rate.net_in > 0.0 && rate.net_in <= 1000.0 / 0.05);
+        assert!(rate.block_write > 0.0);
+        assert_eq!(rate.net_out, 0.0);
+    }
+
+    #[test]
+    fn a_counter_reset_yields_zero() {
+        let mut rates = RateTracker::default();
+        rates.update(&sample(5000, 5000));
+        std::thread::sleep(Duration::from_millis(10));
+        let rate = rates.update(&sample(100, 6000)).unwrap();
+        assert_eq!(rate.net_in, 0.0);
+        assert!(rate.block_write > 0.0);
+    }
+
+    #[test]
+    fn removed_and_stale_containers_start_over() {
+        let mut rates = RateTracker::default();
+        rates.update(&sample(0, 0));
+        rates.remove("web", "abc123");
+        assert!(rates.update(&sample(1000, 0)).is_none());
+        assert_eq!(rates.count_within(Duration::from_secs(60)), 1);
+        std::thread::sleep(Duration::from_millis(10));
+        rates.evict_older_than(Duration::ZERO);
+        assert_eq!(rates.count_within(Duration::from_secs(60)), 0);
+    }
+}
@@ -0,0 +1,123 @@
+//! OpenTelemetry (OTLP) metrics exporter, over gRPC or HTTP with protobuf
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::warn;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Gauge, Meter, MeterProvider};
+use opentelemetry_otlp::{MetricExporter, Protocol, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider, Temporality};
+
+use crate::Sample;
+use super::{Sink, gauge_values};
+
+/// Path of the metrics endpoint of an OTLP/HTTP receiver
+const HTTP_METRICS_PATH: &str = "/v1/metrics";
+
+/// Exports are triggered by [`OtlpSink::flush`], the reader's own timer is
+/// only a fallback that never fires in practice
+const READER_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Records every sample in OTLP gauges named `{prefix}_{metric}` with `name`,
+/// `id`, `extra_labels` and `node_labels` attributes, which are exported on every flush.
+/// Delta temporality makes each export carry only the containers recorded
+/// since the previous one, so stopped containers disappear by themselves.
+pub(crate) struct OtlpSink {
+    provider: SdkMeterProvider,
+    meter: Meter,
+    prefix: String,
+    extra_labels: Vec<String>,
+    node_labels: Vec<(String, String)>,
+    /// By metric name, created on first use
+    gauges: HashMap<&'static str, Gauge<f64>>,
+}
+impl OtlpSink {
+    /// Export to `endpoint` with `protocol` (`grpc` or `http`). An HTTP
+    /// endpoint without a path gets the standard `/v1/metrics`.
+    pub(crate) fn new(
+        endpoint: &str,
+        protocol: &str,
+        prefix: &str,
+        extra_labels: &[String],
+        node_labels: &[(String, String)],
+    ) -> Result<Self, String> {
+        let exporter = match protocol {
+            "http" => {
+                let endpoint = match reqwest::Url::parse(endpoint) {
+                    Ok(url) if url.path() == "/" => format!("{}{}", endpoint.trim_end_matches('/'), HTTP_METRICS_PATH),
+                    _ => endpoint.to_string(),
+                };
+                MetricExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary)
+                    .with_endpoint(endpoint)
+                    .with_temporality(Temporality::Delta)
+                    .build()
+            }
+            _ => MetricExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .with_temporality(Temporality::Delta)
+                .build(),
+        }
+        .map_err(|e| format!("Cannot set up the OTLP exporter for {}: {}", endpoint, e))?;
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter).with_interval(READER_INTERVAL).build())
+            .with_resource(Resource::builder().with_service_name("docker_metrics_exporter").build())
+            .build();
+        let meter = provider.meter("docker_metrics_exporter");
+        Ok(OtlpSink {
+            provider,
+            meter,
+            prefix: prefix.to_string(),
+            extra_labels: extra_labels.to_vec(),
+            node_labels: node_labels.to_vec(),
+            gauges: HashMap::new(),
+        })
+    }
+
+    fn record_gauge(&mut self, metric: &'static str, value: f64, attributes: &[KeyValue]) {
+        let (meter, prefix) = (&self.meter, &self.prefix);
+        self.gauges.entry(metric)
+            .or_insert_with(|| meter.f64_gauge(format!("{}_{}", prefix, metric)).build())
+            .record(value, attributes);
+    }
+}
+impl Sink for OtlpSink {
+    async fn record(&mut self, sample: &Sample) {
+        let mut attributes = vec![KeyValue::new("name", sample.name.clone()), KeyValue::new("id", sample.id.clone())];
+        for label in &self.extra_labels {
+            let value = sample.extra_label(label);
+            if !value.is_empty() {
+                attributes.push(KeyValue::new(label.clone(), value.to_string()));
+            }
+        }
+        attributes.extend(self.node_labels.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone())));
+        for (metric, value) in gauge_values(sample) {
+            self.record_gauge(metric, value, &attributes);
+        }
+        for (interface, &(rx, tx)) in &sample.net_interfaces {
+            let attributes = [attributes.as_slice(), &[KeyValue::new("interface", interface.clone())]].concat();
+            self.record_gauge("net_interface_input_bytes", rx as f64, &attributes);
+            self.record_gauge("net_interface_output_bytes", tx as f64, &attributes);
+        }
+        for (device, &(read, write)) in &sample.block_devices {
+            let attributes = [attributes.as_slice(), &[KeyValue::new("device", device.clone())]].concat();
+            self.record_gauge("block_device_read_bytes", read as f64, &attributes);
+            self.record_gauge("block_device_write_bytes", write as f64, &attributes);
+        }
+    }
+
+    /// Export everything recorded since the last flush
+    async fn flush(&mut self) {
+        // Blocks until the export is done
+        let provider = self.provider.clone();
+        match tokio::task::spawn_blocking(move || provider.force_flush()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("OTLP export error: {}", e),
+            Err(e) => warn!("OTLP export error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,692 @@
+//! Prometheus gauges, served by the HTTP endpoint in `main`
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use prometheus::proto::{MetricFamily, MetricType};
+use log::warn;
+use prometheus::core::{Collector, Desc};
+use prometheus::{Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+
+use crate::{HEALTH_STATUSES, Sample};
+use super::{RateTracker, Sink};
+
+/// How long the series of a container that went away after an OOM kill are
+/// kept, at least (see [`crate::inspect::Inspector::oom_killed`])
+const OOM_GRACE: Duration = Duration::from_secs(300);
+
+pub(crate) struct Metrics {
+    cpu: GaugeVec,
+    /// Only registered with `--smooth`
+    cpu_smoothed: GaugeVec,
+    cpu_cores: GaugeVec,
+    mem_usage: GaugeVec,
+    mem_limit: GaugeVec,
+    mem_limited: GaugeVec,
+    mem_percent: GaugeVec,
+    /// `--backend api` only
+    mem_cache: GaugeVec,
+    mem_rss: GaugeVec,
+    mem_swap: GaugeVec,
+    net_in: GaugeVec,
+    net_out: GaugeVec,
+    block_read: GaugeVec,
+    block_write: GaugeVec,
+    pids: GaugeVec,
+    /// `--backend api` only, set to the daemon's counters and registered as [`AsCounters`]
+    cpu_throttled_periods: GaugeVec,
+    cpu_throttled_seconds: GaugeVec,
+    net_in_rate: GaugeVec,
+    net_out_rate: GaugeVec,
+    block_read_rate: GaugeVec,
+    block_write_rate: GaugeVec,
+    uptime: GaugeVec,
+    running: GaugeVec,
+    restart_count: GaugeVec,
+    oom_killed: GaugeVec,
+    /// With an additional `status` label, one series per [`HEALTH_STATUSES`]
+    health_status: GaugeVec,
+    health_failing_streak: GaugeVec,
+    /// Per interface and per device, with an additional `interface`/`device` label
+    net_interface_in: GaugeVec,
+    net_interface_out: GaugeVec,
+    block_device_read: GaugeVec,
+    block_device_write: GaugeVec,
+    last_scrape: Gauge,
+    /// Label sets in `last_seen`
+    containers: IntGauge,
+    /// Samples of containers that were not added because of `max_series`
+    series_dropped: IntCounter,
+    /// Added after `name` and `id`, see [`crate::EXTRA_LABELS`]
+    extra_labels: Vec<String>,
+    rates: RateTracker,
+    /// Weight of the newest reading in `cpu_smoothed`
+    smooth: Option<f64>,
+    /// Last smoothed CPU value per label set
+    smoothed: HashMap<Vec<String>, f64>,
+    /// Last update per label set, used to evict stopped containers
+    last_seen: HashMap<Vec<String>, Instant>,
+    /// Label sets of OOM-killed containers not to evict before the given time, see [`OOM_GRACE`]
+    oom_held: HashMap<Vec<String>, Instant>,
+    /// Interfaces and devices last reported per label set
+    io_series: HashMap<Vec<String>, (Vec<String>, Vec<String>)>,
+    /// Label sets held at most, unlimited if `None`
+    max_series: Option<usize>,
+    /// Whether the limit was hit since there was last room, to warn once
+    at_limit: bool,
+    /// `--cardinality-alert-rate`
+    growth: Option<GrowthWatch>,
+    /// `--disable-metric` names, see [`crate::METRIC_NAMES`]
+    disabled: Vec<String>,
+}
+impl Metrics {
+    /// Register all gauges in `registry`, named `{prefix}_...`. With `smooth`
+    /// an exponentially weighted moving average of the CPU usage is added.
+    /// While `max_series` containers are held, new ones are dropped. More
+    /// than `alert_rate` new ones within a minute are warned about. The
+    /// gauges of `disabled` metrics are neither registered nor updated.
+    pub(crate) fn new(
+        registry: &Registry,
+        extra_labels: &[String],
+        prefix: &str,
+        smooth: Option<f64>,
+        max_series: Option<usize>,
+        alert_rate: Option<usize>,
+        disabled: &[String],
+    ) -> Self {
+        let enabled = |metric: &str| !disabled.iter().any(|d| d == metric);
+        let opts = |name: &str, help: &str| prometheus::Opts::new(name, help).namespace(prefix);
+        let labels: Vec<&str> = ["name", "id"].into_iter().chain(extra_labels.iter().map(String::as_str)).collect();
+        let labels = labels.as_slice();
+        let cpu = GaugeVec::new(opts("cpu_percent", "CPU usage %, 100 per busy CPU so above 100 with several (unless --cpu-mode normalized)"), labels).unwrap();
+        let cpu_smoothed = GaugeVec::new(opts("cpu_percent_smoothed", "docker_cpu_percent, exponentially smoothed (--smooth)"), labels).unwrap();
+        if smooth.is_some() && enabled("cpu_percent") {
+            registry.register(Box::new(cpu_smoothed.clone())).unwrap();
+        }
+        if enabled("cpu_percent") {
+            let opts = HistogramOpts::new("cpu_percent_distribution", "CPU usage % of the current containers")
+                .namespace(prefix)
+                .buckets(CPU_BUCKETS.to_vec());
+            registry.register(Box::new(CpuDistribution::new(opts, cpu.clone()))).unwrap();
+        }
+        let cpu_cores = GaugeVec::new(opts("cpu_cores", "CPUs available to the container"), labels).unwrap();
+        let mem_usage = GaugeVec::new(opts("mem_usage_bytes", "Memory currently used"), labels).unwrap();
+        let mem_limit = GaugeVec::new(opts("mem_limit_bytes", "Memory limit of the container"), labels).unwrap();
+        let mem_percent = GaugeVec::new(opts("mem_percent", "Memory currently used, in % of the limit (or of the host memory without one)"), labels).unwrap();
+        let mem_limited = GaugeVec::new(opts("mem_limited", "1 if the container has a memory limit"), labels).unwrap();
+        let mem_cache = GaugeVec::new(opts("mem_cache_bytes", "Page cache memory currently used"), labels).unwrap();
+        let mem_rss = GaugeVec::new(opts("mem_rss_bytes", "Anonymous memory (RSS) currently used"), labels).unwrap();
+        let mem_swap = GaugeVec::new(opts("mem_swap_bytes", "Swap currently used"), labels).unwrap();
+        let net_in = GaugeVec::new(opts("net_input_bytes", "Bytes received over the network since the container started"), labels).unwrap();
+        let net_out = GaugeVec::new(opts("net_output_bytes", "Bytes sent over the network since the container started"), labels).unwrap();
+        let block_read = GaugeVec::new(opts("block_read_bytes", "Bytes read from block devices since the container started"), labels).unwrap();
+        let block_write = GaugeVec::new(opts("block_write_bytes", "Bytes written to block devices since the container started"), labels).unwrap();
+        let pids = GaugeVec::new(opts("pids", "Processes and threads currently running in the container"), labels).unwrap();
+        let cpu_throttled_periods = GaugeVec::new(opts("cpu_throttled_periods_total", "CFS periods in which the container was throttled"), labels).unwrap();
+        let cpu_throttled_seconds = GaugeVec::new(opts("cpu_throttled_seconds_total", "Time the container was throttled by its CPU quota"), labels).unwrap();
+        let net_in_rate = GaugeVec::new(opts("net_input_bytes_per_sec", "Bytes received per second between the last two samples"), labels).unwrap();
+        let net_out_rate = GaugeVec::new(opts("net_output_bytes_per_sec", "Bytes sent per second between the last two samples"), labels).unwrap();
+        let block_read_rate = GaugeVec::new(opts("block_read_bytes_per_sec", "Bytes read per second between the last two samples"), labels).unwrap();
+        let block_write_rate = GaugeVec::new(opts("block_write_bytes_per_sec", "Bytes written per second between the last two samples"), labels).unwrap();
+        let uptime = GaugeVec::new(opts("uptime_seconds", "Time since the container started"), labels).unwrap();
+        let restart_count = GaugeVec::new(opts("restart_count", "Restarts by the restart policy, from docker inspect"), labels).unwrap();
+        let oom_killed = GaugeVec::new(opts("oom_killed", "1 if the last run of the container was ended by the OOM killer, from docker inspect"), labels).unwrap();
+        let health_status = GaugeVec::new(opts("health_status", "1 for the current HEALTHCHECK status of the container, 0 for the others"), &with_label(labels, "status")).unwrap();
+        let health_failing_streak = GaugeVec::new(opts("health_failing_streak", "Failed HEALTHCHECK runs in a row"), labels).unwrap();
+        let running = GaugeVec::new(opts("container_running", "1 if the container is running, 0 if stopped (--include-stopped)"), labels).unwrap();
+        let net_interface_in = GaugeVec::new(opts("net_interface_input_bytes", "Bytes received per network interface since the container started"), &with_label(labels, "interface")).unwrap();
+        let net_interface_out = GaugeVec::new(opts("net_interface_output_bytes", "Bytes sent per network interface since the container started"), &with_label(labels, "interface")).unwrap();
+        let block_device_read = GaugeVec::new(opts("block_device_read_bytes", "Bytes read per block device since the container started"), &with_label(labels, "device")).unwrap();
+        let block_device_write = GaugeVec::new(opts("block_device_write_bytes", "Bytes written per block device since the container started"), &with_label(labels, "device")).unwrap();
+        for (m, metric) in [
+            (&cpu, "cpu_percent"), (&cpu_cores, "cpu_cores"),
+            (&mem_usage, "mem_usage"), (&mem_limit, "mem_limit"), (&mem_limited, "mem_limited"), (&mem_percent, "mem_percent"),
+            (&mem_cache, "mem_cache"), (&mem_rss, "mem_rss"), (&mem_swap, "mem_swap"),
+            (&net_in, "net_input"), (&net_out, "net_output"), (&block_read, "block_read"), (&block_write, "block_write"), (&pids, "pids"),
+            (&net_in_rate, "net_input"), (&net_out_rate, "net_output"), (&block_read_rate, "block_read"), (&block_write_rate, "block_write"),
+            (&uptime, "uptime_seconds"), (&running, "running"), (&restart_count, "restart_count"), (&oom_killed, "oom_killed"),
+            (&health_status, "health"), (&health_failing_streak, "health"),
+            (&net_interface_in, "net_input"), (&net_interface_out, "net_output"),
+            (&block_device_read, "block_read"), (&block_device_write, "block_write"),
+        ] {
+            if enabled(metric) {
+                registry.register(Box::new(m.clone())).unwrap();
+            }
+        }
+        if enabled("cpu_throttled") {
+            registry.register(Box::new(AsCounters(cpu_throttled_periods.clone()))).unwrap();
+            registry.register(Box::new(AsCounters(cpu_throttled_seconds.clone()))).unwrap();
+        }
+
+        let last_scrape = Gauge::with_opts(opts("last_scrape_timestamp_seconds", "When stats were last processed")).unwrap();
+        let start_time = Gauge::with_opts(opts("exporter_start_time_seconds", "When the exporter was started")).unwrap();
+        start_time.set(unix_time());
+        registry.register(Box::new(last_scrape.clone())).unwrap();
+        registry.register(Box::new(start_time)).unwrap();
+        let containers = IntGauge::with_opts(opts("containers_total", "Containers with live series")).unwrap();
+        registry.register(Box::new(containers.clone())).unwrap();
+        let series_dropped = IntCounter::with_opts(opts("series_dropped_total", "Samples of new containers dropped because of --max-series")).unwrap();
+        registry.register(Box::new(series_dropped.clone())).unwrap();
+        let growth = alert_rate.map(|limit| {
+            let warning = IntGauge::with_opts(opts("cardinality_growth_warning", "1 while more than --cardinality-alert-rate containers showed up within the last minute")).unwrap();
+            registry.register(Box::new(warning.clone())).unwrap();
+            GrowthWatch { limit, added: VecDeque::new(), warned: None, warning }
+        });
+        Metrics {
+            cpu, cpu_smoothed, cpu_cores, mem_usage, mem_limit, mem_limited, mem_percent, mem_cache, mem_rss, mem_swap, net_in, net_out, block_read, block_write, pids,
+            cpu_throttled_periods, cpu_throttled_seconds,
+            net_in_rate, net_out_rate, block_read_rate, block_write_rate, uptime, running, restart_count, oom_killed,
+            health_status, health_failing_streak,
+            net_interface_in, net_interface_out, block_device_read, block_device_write,
+            last_scrape,
+            containers,
+            series_dropped,
+            extra_labels: extra_labels.to_vec(),
+            rates: RateTracker::default(),
+            smooth,
+            smoothed: HashMap::new(),
+            last_seen: HashMap::new(),
+            oom_held: HashMap::new(),
+            io_series: HashMap::new(),
+            max_series,
+            at_limit: false,
+            growth,
+            disabled: disabled.to_vec(),
+        }
+    }
+
+    fn enabled(&self, metric: &str) -> bool {
+        !self.disabled.iter().any(|d| d == metric)
+    }
+    fn gauges(&self) -> [&GaugeVec; 26] {
+        [&self.cpu, &self.cpu_smoothed, &self.cpu_cores, &self.mem_usage, &self.mem_limit, &self.mem_limited, &self.mem_percent,
+         &self.mem_cache, &self.mem_rss, &self.mem_swap, &self.net_in, &self.net_out, &self.block_read, &self.block_write, &self.pids,
+         &self.cpu_throttled_periods, &self.cpu_throttled_seconds,
+         &self.net_in_rate, &self.net_out_rate, &self.block_read_rate, &self.block_write_rate, &self.uptime, &self.running, &self.restart_count,
+         &self.oom_killed, &self.health_failing_streak]
+    }
+    pub(super) fn update(&mut self, sample: &Sample) {
+        let labels: Vec<&str> = [sample.name.as_str(), sample.id.as_str()].into_iter()
+            .chain(self.extra_labels.iter().map(|l| sample.extra_label(l)))
+            .collect();
+        let key: Vec<String> = labels.iter().map(|l| l.to_string()).collect();
+        if let Some(max) = self.max_series
+            && !self.last_seen.contains_key(&key)
+        {
+            if self.last_seen.len() >= max {
+                if !self.at_limit {
+                    warn!("--max-series {} reached, dropping samples of new containers like {} until others go away", max, sample.name);
+                    self.at_limit = true;
+                }
+                self.series_dropped.inc();
+                return;
+            }
+            self.at_limit = false;
+        }
+        if self.enabled("cpu_percent") {
+            self.cpu.with_label_values(&labels).set(sample.cpu);
+            if let Some(alpha) = self.smooth {
+                let smoothed = match self.smoothed.get(&key) {
+                    Some(prev) => alpha * sample.cpu + (1.0 - alpha) * prev,
+                    None => sample.cpu,
+                };
+                self.smoothed.insert(key.clone(), smoothed);
+                self.cpu_smoothed.with_label_values(&labels).set(smoothed);
+            }
+        }
+        if let Some(cores) = sample.cpu_cores.filter(|_| self.enabled("cpu_cores")) {
+            self.cpu_cores.with_label_values(&labels).set(cores);
+        }
+        if self.enabled("mem_usage") {
+            self.mem_usage.with_label_values(&labels).set(sample.mem_usage as f64);
+        }
+        if self.enabled("mem_limit") {
+            match sample.mem_limit {
+                Some(limit) => self.mem_limit.with_label_values(&labels).set(limit as f64),
+                None => { let _ = self.mem_limit.remove_label_values(&labels); }
+            }
+        }
+        if let Some(percent) = sample.mem_percent.filter(|_| self.enabled("mem_percent")) {
+            self.mem_percent.with_label_values(&labels).set(percent);
+        }
+        if self.enabled("mem_limited") {
+            self.mem_limited.with_label_values(&labels).set(if sample.mem_limit.is_some() { 1.0 } else { 0.0 });
+        }
+        let values = [
+            (&self.mem_cache, "mem_cache", sample.mem_cache.map(|b| b as f64)),
+            (&self.mem_rss, "mem_rss", sample.mem_rss.map(|b| b as f64)),
+            (&self.mem_swap, "mem_swap", sample.mem_swap.map(|b| b as f64)),
+            (&self.net_in, "net_input", Some(sample.net_in as f64)),
+            (&self.net_out, "net_output", Some(sample.net_out as f64)),
+            (&self.block_read, "block_read", Some(sample.block_read as f64)),
+            (&self.block_write, "block_write", Some(sample.block_write as f64)),
+            (&self.pids, "pids", Some(sample.pids as f64)),
+            (&self.cpu_throttled_periods, "cpu_throttled", sample.cpu_throttled_periods.map(|p| p as f64)),
+            (&self.cpu_throttled_seconds, "cpu_throttled", sample.cpu_throttled_seconds),
+        ];
+        for (gauge, metric, value) in values {
+            if let Some(value) = value.filter(|_| self.enabled(metric)) {
+                gauge.with_label_values(&labels).set(value);
+            }
+        }
+        if let Some(rates) = self.rates.update(sample) {
+            for (gauge, metric, rate) in [
+                (&self.net_in_rate, "net_input", rates.net_in),
+                (&self.net_out_rate, "net_output", rates.net_out),
+                (&self.block_read_rate, "block_read", rates.block_read),
+                (&self.block_write_rate, "block_write", rates.block_write),
+            ] {
+                if self.enabled(metric) {
+                    gauge.with_label_values(&labels).set(rate);
+                }
+            }
+        }
+        if self.enabled("uptime_seconds") {
+            match sample.uptime() {
+                Some(uptime) => self.uptime.with_label_values(&labels).set(uptime),
+                None => { let _ = self.uptime.remove_label_values(&labels); }
+            }
+        }
+        if let Some(restarts) = sample.restart_count.filter(|_| self.enabled("restart_count")) {
+            self.restart_count.with_label_values(&labels).set(restarts as f64);
+        }
+        if let Some(oom_killed) = sample.oom_killed.filter(|_| self.enabled("oom_killed")) {
+            self.oom_killed.with_label_values(&labels).set(if oom_killed { 1.0 } else { 0.0 });
+        }
+        if self.enabled("health") {
+            match (&sample.health_status, sample.health_failing_streak) {
+                (Some(current), Some(streak)) => {
+                    for status in HEALTH_STATUSES {
+                        self.health_status.with_label_values(&with_label(&labels, status)).set(if status == current { 1.0 } else { 0.0 });
+                    }
+                    self.health_failing_streak.with_label_values(&labels).set(streak as f64);
+                }
+                // Not inspected (yet), or the healthcheck went away with a new image
+                _ => {
+                    self.remove_health_series(&labels);
+                    let _ = self.health_failing_streak.remove_label_values(&labels);
+                }
+            }
+        }
+        if self.enabled("running") {
+            self.running.with_label_values(&labels).set(if sample.stopped { 0.0 } else { 1.0 });
+        }
+        let (net_in, net_out) = (self.enabled("net_input"), self.enabled("net_output"));
+        for (interface, &(rx, tx)) in &sample.net_interfaces {
+            if net_in {
+                self.net_interface_in.with_label_values(&with_label(&labels, interface)).set(rx as f64);
+            }
+            if net_out {
+                self.net_interface_out.with_label_values(&with_label(&labels, interface)).set(tx as f64);
+            }
+        }
+        let (block_read, block_write) = (self.enabled("block_read"), self.enabled("block_write"));
+        for (device, &(read, write)) in &sample.block_devices {
+            if block_read {
+                self.block_device_read.with_label_values(&with_label(&labels, device)).set(read as f64);
+            }
+            if block_write {
+                self.block_device_write.with_label_values(&with_label(&labels, device)).set(write as f64);
+            }
+        }
+        let io = (sample.net_interfaces.keys().cloned().collect(), sample.block_devices.keys().cloned().collect());
+        if let Some((interfaces, devices)) = self.io_series.insert(key.clone(), io) {
+            // Interfaces can go away while the container keeps running
+            let gone = |names: &[String], current: &BTreeMap<String, (u64, u64)>| -> Vec<String> {
+                names.iter().filter(|n| !current.contains_key(*n)).cloned().collect()
+            };
+            self.remove_io_series(&labels, &gone(&interfaces, &sample.net_interfaces), &gone(&devices, &sample.block_devices));
+        }
+        match sample.stopped && sample.oom_killed == Some(true) {
+            true => { self.oom_held.insert(key.clone(), Instant::now() + OOM_GRACE); }
+            false => { self.oom_held.remove(&key); }
+        }
+        let added = self.last_seen.insert(key, Instant::now()).is_none();
+        if let Some(growth) = &mut self.growth {
+            growth.update(added, self.last_seen.len());
+        }
+        self.containers.set(self.last_seen.len() as i64);
+        self.last_scrape.set(unix_time());
+    }
+    /// Remove all series of containers that have not been updated within
+    /// `timeout`, those of OOM-killed ones only after [`OOM_GRACE`]
+    pub(super) fn evict_stale(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let stale: Vec<Vec<String>> = self.last_seen.iter()
+            .filter(|(key, seen)| seen.elapsed() > timeout && self.oom_held.get(*key).is_none_or(|&until| now >= until))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            let labels: Vec<&str> = key.iter().map(String::as_str).collect();
+            for g in self.gauges() {
+                let _ = g.remove_label_values(&labels);
+            }
+            if let Some((interfaces, devices)) = self.io_series.remove(&key) {
+                self.remove_io_series(&labels, &interfaces, &devices);
+            }
+            self.remove_health_series(&labels);
+            self.smoothed.remove(&key);
+            self.rates.remove(&key[0], &key[1]);
+            self.last_seen.remove(&key);
+            self.oom_held.remove(&key);
+        }
+        self.containers.set(self.last_seen.len() as i64);
+    }
+
+    fn remove_health_series(&self, labels: &[&str]) {
+        for status in HEALTH_STATUSES {
+            let _ = self.health_status.remove_label_values(&with_label(labels, status));
+        }
+    }
+
+    fn remove_io_series(&self, labels: &[&str], interfaces: &[String], devices: &[String]) {
+        for interface in interfaces {
+            let _ = self.net_interface_in.remove_label_values(&with_label(labels, interface));
+            let _ = self.net_interface_out.remove_label_values(&with_label(labels, interface));
+        }
+        for device in devices {
+            let _ = self.block_device_read.remove_label_values(&with_label(labels, device));
+            let _ = self.block_device_write.remove_label_values(&with_label(labels, device));
+        }
+    }
+}
+
+/// Upper bounds of the `cpu_percent_distribution` buckets, `+Inf` is added
+const CPU_BUCKETS: [f64; 8] = [0.0, 5.0, 10.0, 25.0, 50.0, 75.0, 100.0, 200.0];
+
+/// A histogram of the `cpu_percent` gauges, built afresh on every scrape so
+/// that it covers exactly the containers that currently have series
+struct CpuDistribution {
+    opts: HistogramOpts,
+    /// Never observed, only provides the description
+    empty: Histogram,
+    cpu: GaugeVec,
+}
+impl CpuDistribution {
+    fn new(opts: HistogramOpts, cpu: GaugeVec) -> Self {
+        let empty = Histogram::with_opts(opts.clone()).unwrap();
+        CpuDistribution { opts, empty, cpu }
+    }
+}
+impl Collector for CpuDistribution {
+    fn desc(&self) -> Vec<&Desc> {
+        self.empty.desc()
+    }
+    fn collect(&self) -> Vec<MetricFamily> {
+        let histogram = Histogram::with_opts(self.opts.clone()).unwrap();
+        for family in self.cpu.collect() {
+            for metric in family.get_metric() {
+                histogram.observe(metric.get_gauge().get_value());
+            }
+        }
+        histogram.collect()
+    }
+}
+
+/// Exposes a [`GaugeVec`] as counters, for values that are counter readings
+/// taken from the daemon: a `CounterVec` only takes increments
+struct AsCounters(GaugeVec);
+impl Collector for AsCounters {
+    fn desc(&self) -> Vec<&Desc> {
+        self.0.desc()
+    }
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.0.collect();
+        for family in &mut families {
+            family.set_field_type(MetricType::COUNTER);
+            for metric in family.mut_metric().iter_mut() {
+                let mut counter = prometheus::proto::Counter::default();
+                counter.set_value(metric.get_gauge().get_value());
+                metric.set_counter(counter);
+                metric.clear_gauge();
+            }
+        }
+        families
+    }
+}
+
+/// `labels` followed by `extra`
+fn with_label<'a>(labels: &[&'a str], extra: &'a str) -> Vec<&'a str> {
+    labels.iter().copied().chain([extra]).collect()
+}
+
+/// Current time as fractional seconds since the epoch
+fn unix_time() -> f64 {
+    Utc::now().timestamp_millis() as f64 / 1000.0
+}
+
+/// Keeps the Prometheus gauges up to date and evicts stopped containers
+pub(crate) struct PrometheusSink {
+    pub(crate) metrics: Metrics,
+    pub(crate) stale_timeout: Duration,
+    /// Held while the gauges of a sample are updated or stale series are
+    /// removed. Every gauge has a lock of its own, so without it a scrape
+    /// could see e.g. the new CPU and the old memory of a container, or half
+    /// of its series evicted; scrapes take it around [`render`].
+    pub(crate) update_lock: Arc<Mutex<()>>,
+}
+impl Sink for PrometheusSink {
+    async fn record(&mut self, sample: &Sample) {
+        let _guard = self.update_lock.lock().unwrap();
+        self.metrics.update(sample);
+    }
+    async fn flush(&mut self) {
+        let _guard = self.update_lock.lock().unwrap();
+        self.metrics.evict_stale(self.stale_timeout);
+    }
+}
+
+/// Content type of the OpenMetrics text format
+const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encode everything in `registry` for a scrape, in the OpenMetrics text
+/// format if `openmetrics` and the classic Prometheus text format otherwise.
+/// Returns the content type and the body.
+pub(crate) fn render(registry: &Registry, openmetrics: bool) -> (&'static str, String) {
+    let families = registry.gather();
+    if openmetrics {
+        return (OPENMETRICS_FORMAT, encode_openmetrics(&families));
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&families, &mut buffer).unwrap();
+    (prometheus::TEXT_FORMAT, String::from_utf8(buffer).unwrap())
+}
+
+/// Window of [`GrowthWatch`], also the least time between two of its warnings
+const GROWTH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Counts the label sets added within the last [`GROWTH_WINDOW`], a soft
+/// early warning for runaway containers before `--max-series` drops samples
+struct GrowthWatch {
+    /// New label sets per window that are still fine
+    limit: usize,
+    /// When the label sets of the window were added, oldest first
+    added: VecDeque<Instant>,
+    warned: Option<Instant>,
+    warning: IntGauge,
+}
+impl GrowthWatch {
+    /// Called for every sample, `added` if it was of a new label set
+    fn update(&mut self, added: bool, series: usize) {
+        let now = Instant::now();
+        while self.added.front().is_some_and(|&at| now.duration_since(at) > GROWTH_WINDOW) {
+            self.added.pop_front();
+        }
+        if added {
+            self.added.push_back(now);
+        }
+        let growing = self.added.len() > self.limit;
+        self.warning.set(growing as i64);
+        if growing && self.warned.is_none_or(|warned| now.duration_since(warned) >= GROWTH_WINDOW) {
+            warn!("{} new containers within a minute (--cardinality-alert-rate {}), {} exported now", self.added.len(), self.limit, series);
+            self.warned = Some(now);
+        }
+    }
+}
+
+/// Units announced with `# UNIT` in OpenMetrics, for the families named with
+/// them as the last part. Only base units are allowed there, so the
+/// `_percent` families go without one
+const UNITS: [&str; 2] = ["bytes", "seconds"];
+
+/// The prometheus crate only speaks the classic text format. OpenMetrics
+/// differs for the metric types used here in that counter families are named
+/// without their `_total` suffix, families can carry a unit, and the output
+/// ends with `# EOF`. Histograms are written the same way in both.
+fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let (kind, name) = match family.get_field_type() {
+            MetricType::COUNTER => ("counter", family.get_name().strip_suffix("_total").unwrap_or(family.get_name())),
+            MetricType::GAUGE => ("gauge", family.get_name()),
+            MetricType::HISTOGRAM => ("histogram", family.get_name()),
+            // Summaries are never registered by the exporter
+            _ => continue,
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, escape(family.get_help()));
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        if let Some(unit) = UNITS.iter().find(|unit| name.ends_with(&format!("_{}", unit))) {
+            let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        }
+        for metric in family.get_metric() {
+            let labels: Vec<String> = metric.get_label().iter()
+                .map(|l| format!("{}=\"{}\"", l.get_name(), escape(l.get_value())))
+                .collect();
+            if kind == "histogram" {
+                let histogram = metric.get_histogram();
+                let bucket_labels = |le: &str| format!("{{{}}}", labels.iter().cloned().chain([format!("le=\"{}\"", le)]).collect::<Vec<_>>().join(","));
+                for bucket in histogram.get_bucket() {
+                    let _ = writeln!(out, "{}_bucket{} {}", name, bucket_labels(&format_bound(bucket.get_upper_bound())), bucket.get_cumulative_count());
+                }
+                let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) };
+                let _ = writeln!(out, "{}_bucket{} {}", name, bucket_labels("+Inf"), histogram.get_sample_count());
+                let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.get_sample_count());
+                let _ = writeln!(out, "{}_sum{} {}", name, labels, format_value(histogram.get_sample_sum()));
+                continue;
+            }
+            let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels.join(",")) };
+            let (suffix, value) = match kind {
+                "counter" => ("_total", metric.get_counter().get_value()),
+                _ => ("", metric.get_gauge().get_value()),
+            };
+            let _ = writeln!(out, "{}{}{} {}", name, suffix, labels, format_value(value));
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// OpenMetrics wants the canonical float form of bucket bounds (`le="5.0"`,
+/// not `le="5"`), which `Debug` prints
+fn format_bound(bound: f64) -> String {
+    if bound.is_infinite() { format_value(bound) } else { format!("{:?}", bound) }
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn families() -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        let restarts = IntCounter::new("docker_restarts_total", "Restarts").unwrap();
+        restarts.inc_by(3);
+        registry.register(Box::new(restarts)).unwrap();
+        let mem = GaugeVec::new(prometheus::Opts::new("docker_mem_usage_bytes", "Memory \"used\""), &["name"]).unwrap();
+        mem.with_label_values(&["web"]).set(1024.0);
+        registry.register(Box::new(mem)).unwrap();
+        let cpu = Gauge::new("docker_cpu_percent", "CPU").unwrap();
+        cpu.set(1.5);
+        registry.register(Box::new(cpu)).unwrap();
+        let histogram = Histogram::with_opts(HistogramOpts::new("docker_cpu_percent_distribution", "CPU").buckets(vec![5.0, 10.0])).unwrap();
+        histogram.observe(7.5);
+        registry.register(Box::new(histogram)).unwrap();
+        registry.gather()
+    }
+
+    #[test]
+    fn openmetrics_names_counter_families_without_total() {
+        let out = encode_openmetrics(&families());
+        assert!(out.contains("# TYPE docker_restarts counter\ndocker_restarts_total 3\n"));
+        assert!(out.contains("# HELP docker_mem_usage_bytes Memory \\\"used\\\"\n"));
+        assert!(out.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn openmetrics_announces_only_base_units() {
+        let out = encode_openmetrics(&families());
+        assert!(out.contains("# TYPE docker_mem_usage_bytes gauge\n# UNIT docker_mem_usage_bytes bytes\ndocker_mem_usage_bytes{name=\"web\"} 1024\n"));
+        assert!(!out.contains("# UNIT docker_cpu_percent"));
+    }
+
+    #[test]
+    fn openmetrics_bucket_bounds_are_floats() {
+        let out = encode_openmetrics(&families());
+        assert!(out.contains("docker_cpu_percent_distribution_bucket{le=\"5.0\"} 0\n"));
+        assert!(out.contains("docker_cpu_percent_distribution_bucket{le=\"10.0\"} 1\n"));
+        assert!(out.contains("docker_cpu_percent_distribution_bucket{le=\"+Inf\"} 1\n"));
+        assert!(out.contains("docker_cpu_percent_distribution_count 1\ndocker_cpu_percent_distribution_sum 7.5\n"));
+    }
+
+    fn metrics(registry: &Registry) -> Metrics {
+        Metrics::new(registry, &[], "docker", None, None, None, &[])
+    }
+
+    fn sample(name: &str, stopped: bool, oom_killed: bool) -> Sample {
+        Sample { name: name.to_string(), id: name.to_string(), stopped, oom_killed: Some(oom_killed), ..Sample::default() }
+    }
+
+    fn series(registry: &Registry) -> Vec<String> {
+        let families = registry.gather();
+        let oom = families.iter().find(|family| family.get_name() == "docker_oom_killed");
+        let mut names: Vec<String> = oom.map_or(Vec::new(), |family| family.get_metric().iter()
+            .map(|metric| metric.get_label().iter().find(|l| l.get_name() == "name").unwrap().get_value().to_string())
+            .collect());
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn oom_killed_containers_outlive_the_stale_timeout() {
+        let registry = Registry::new();
+        let mut metrics = metrics(&registry);
+        metrics.update(&sample("web", false, false));
+        metrics.update(&sample("worker", true, true));
+        std::thread::sleep(Duration::from_millis(10));
+        metrics.evict_stale(Duration::ZERO);
+        assert_eq!(series(&registry), ["worker"]);
+        // Going by the grace period, not the timeout
+        let key = metrics.oom_held.keys().next().unwrap().clone();
+        metrics.oom_held.insert(key, Instant::now());
+        metrics.evict_stale(Duration::ZERO);
+        assert!(series(&registry).is_empty());
+        assert!(metrics.oom_held.is_empty());
+    }
+
+    #[test]
+    fn a_restarted_container_is_no_longer_held() {
+        let registry = Registry::new();
+        let mut metrics = metrics(&registry);
+        metrics.update(&sample("worker", true, true));
+        metrics.update(&sample("worker", false, true));
+        std::thread::sleep(Duration::from_millis(10));
+        metrics.evict_stale(Duration::ZERO);
+        assert!(series(&registry).is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+//! Prometheus Pushgateway writer for containers too short-lived to be scraped
+
+use std::time::Duration;
+
+use log::error;
+use prometheus::Registry;
+
+use crate::Sample;
+use super::prometheus::{Metrics, render};
+use super::{Sink, http_client, with_retries};
+
+/// Keeps the same gauges as [`super::PrometheusSink`] and PUTs the whole
+/// registry to the grouping key URL on every flush with new samples, which
+/// replaces everything pushed before (so evicted containers disappear).
+pub(crate) struct PushgatewaySink {
+    metrics: Metrics,
+    registry: Registry,
+    http: reqwest::Client,
+    url: reqwest::Url,
+    stale_timeout: Duration,
+    /// Samples were recorded since the last push
+    dirty: bool,
+}
+impl PushgatewaySink {
+    /// Push `registry`, which `metrics` were registered in, to
+    /// `/metrics/job/{job}` of `base_url`, plus `/instance/{instance}` if not empty
+    pub(crate) fn new(
+        registry: Registry,
+        metrics: Metrics,
+        base_url: &str,
+        job: &str,
+        instance: &str,
+        stale_timeout: Duration,
+    ) -> Result<Self, String> {
+        let mut url = reqwest::Url::parse(base_url).map_err(|e| format!("Invalid --pushgateway-url {}: {}", base_url, e))?;
+        {
+            let mut path = url.path_segments_mut().map_err(|_| format!("Invalid --pushgateway-url {}", base_url))?;
+            path.pop_if_empty().extend(["metrics", "job", job]);
+            if !instance.is_empty() {
+                path.extend(["instance", instance]);
+            }
+        }
+        let http = http_client().build().map_err(|e| format!("Failed to set up the Pushgateway client: {}", e))?;
+        Ok(PushgatewaySink {
+            metrics,
+            registry,
+            http,
+            url,
+            stale_timeout,
+            dirty: false,
+        })
+    }
+
+    async fn push(&self) -> Result<(), String> {
+        let (content_type, body) = render(&self.registry, false);
+        let res = self.http.put(self.url.clone())
+            .header("Content-Type", content_type)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = res.text().await.unwrap_or_default();
+        match body.trim() {
+            "" => Err(status.to_string()),
+            body => Err(format!("{}: {}", status, body)),
+        }
+    }
+}
+impl Sink for PushgatewaySink {
+    async fn record(&mut self, sample: &Sample) {
+        self.metrics.update(sample);
+        self.dirty = true;
+    }
+
+    async fn flush(&mut self) {
+        self.metrics.evict_stale(self.stale_timeout);
+        if !self.dirty {
+            return;
+        }
+        // Still dirty after a failure, so the next flush tries again
+        match with_retries("Pushgateway push", || self.push()).await {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Pushgateway push error: {}, trying again on the next flush", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accept one request, answer it with `status` and return it as received
+    async fn serve_once(listener: TcpListener, status: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|n| n.trim().parse::<usize>().unwrap()))
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+            if n == 0 {
+                break;
+            }
+        }
+        stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    fn sink(url: &str, instance: &str) -> PushgatewaySink {
+        let registry = Registry::new();
+        let metrics = Metrics::new(&registry, &[], "docker", None, None, None, &[]);
+        PushgatewaySink::new(registry, metrics, url, "docker", instance, Duration::from_secs(60)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn puts_the_registry_to_the_grouping_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "200 OK"));
+        let mut sink = sink(&url, "web01");
+        sink.record(&Sample { name: "web".to_string(), id: "abc123".to_string(), cpu: 1.5, ..Sample::default() }).await;
+        sink.flush().await;
+        let request = server.await.unwrap();
+        assert!(request.starts_with("PUT /metrics/job/docker/instance/web01 HTTP/1.1\r\n"), "{}", request);
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        assert!(head.to_ascii_lowercase().contains(&format!("content-type: {}", prometheus::TEXT_FORMAT)));
+        assert!(body.contains("# TYPE docker_cpu_percent gauge\n"));
+        assert!(body.contains("docker_cpu_percent{id=\"abc123\",name=\"web\"} 1.5\n"));
+        assert!(!sink.dirty);
+    }
+
+    #[tokio::test]
+    async fn the_instance_is_optional_and_errors_carry_the_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "400 Bad Request"));
+        let sink = sink(&url, "");
+        assert!(sink.push().await.unwrap_err().starts_with("400 Bad Request"));
+        assert!(server.await.unwrap().starts_with("PUT /metrics/job/docker HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Prometheus remote-write sender, for Cortex, Mimir, Thanos Receive and
+//! other receivers that take pushed samples instead of scraping, built with
+//! the `remote-write` feature
+
+use std::time::Duration;
+
+use log::error;
+use prometheus::Registry;
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message;
+
+use crate::Sample;
+use super::prometheus::Metrics;
+use super::{Sink, http_client, with_retries};
+
+/// `prometheus.WriteRequest` of the remote-write 1.0 protocol
+#[derive(Clone, PartialEq, prost::Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct TimeSeries {
+    /// Sorted by name, `__name__` is the metric name
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<RemoteSample>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct RemoteSample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    /// Milliseconds since the epoch
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Keeps the same gauges as [`super::PrometheusSink`] and POSTs all of them
+/// as one snappy compressed write request on every flush with new samples.
+/// Evicted containers are not sent anymore and go stale on the receiver.
+pub(crate) struct RemoteWriteSink {
+    metrics: Metrics,
+    registry: Registry,
+    http: reqwest::Client,
+    url: String,
+    stale_timeout: Duration,
+    /// Samples were recorded since the last write
+    dirty: bool,
+}
+impl RemoteWriteSink {
+    /// Send `registry`, which `metrics` were registered in, to `url`
+    pub(crate) fn new(registry: Registry, metrics: Metrics, url: &str, stale_timeout: Duration) -> Result<Self, String> {
+        let http = http_client().build().map_err(|e| format!("Failed to set up the remote-write client: {}", e))?;
+        Ok(RemoteWriteSink {
+            metrics,
+            registry,
+            http,
+            url: url.to_string(),
+            stale_timeout,
+            dirty: false,
+        })
+    }
+
+    async fn write(&self) -> Result<(), String> {
+        let request = write_request(&self.registry.gather(), chrono::Utc::now().timestamp_millis());
+        let body = encode(&request)?;
+        let res = self.http.post(&self.url)
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = res.text().await.unwrap_or_default();
+        match body.trim() {
+            "" => Err(status.to_string()),
+            body => Err(format!("{}: {}", status, body)),
+        }
+    }
+}
+impl Sink for RemoteWriteSink {
+    async fn record(&mut self, sample: &Sample) {
+        self.metrics.update(sample);
+        self.dirty = true;
+    }
+
+    async fn flush(&mut self) {
+        self.metrics.evict_stale(self.stale_timeout);
+        if !self.dirty {
+            return;
+        }
+        // Still dirty after a failure, so the next flush sends the then current values
+        match with_retries("Remote write", || self.write()).await {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Remote write error: {}, trying again on the next flush", e),
+        }
+    }
+}
+
+/// One series per gauge and counter, and the `_bucket`, `_count` and `_sum`
+/// series of histograms as the text format has them, all timestamped `timestamp`
+fn write_request(families: &[MetricFamily], timestamp: i64) -> WriteRequest {
+    let mut timeseries = Vec::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, String)> = metric.get_label().iter()
+                .map(|l| (l.get_name(), l.get_value().to_string()))
+                .collect();
+            let mut push = |name: String, extra: Option<(&str, String)>, value: f64| {
+                let mut labels: Vec<Label> = labels.iter().cloned().chain(extra)
+                    .map(|(name, value)| Label { name: name.to_string(), value })
+                    .chain([Label { name: "__name__".to_string(), value: name }])
+                    .collect();
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                timeseries.push(TimeSeries { labels, samples: vec![RemoteSample { value, timestamp }] });
+            };
+            let name = family.get_name();
+            match family.get_field_type() {
+                MetricType::GAUGE => push(name.to_string(), None, metric.get_gauge().get_value()),
+                MetricType::COUNTER => push(name.to_string(), None, metric.get_counter().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let le = ("le", bucket.get_upper_bound().to_string());
+                        push(format!("{}_bucket", name), Some(le), bucket.get_cumulative_count() as f64);
+                    }
+                    push(format!("{}_bucket", name), Some(("le", "+Inf".to_string())), histogram.get_sample_count() as f64);
+                    push(format!("{}_count", name), None, histogram.get_sample_count() as f64);
+                    push(format!("{}_sum", name), None, histogram.get_sample_sum());
+                }
+                // Summaries are never registered by the exporter
+                _ => {}
+            }
+        }
+    }
+    WriteRequest { timeseries }
+}
+
+/// `request` as the body of a write: protobuf in the snappy block format
+/// (not the framed one). Debug builds decode it again to check the round trip.
+fn encode(request: &WriteRequest) -> Result<Vec<u8>, String> {
+    let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())
+        .map_err(|e| format!("Snappy compression failed: {}", e))?;
+    debug_assert_eq!(
+        snap::raw::Decoder::new().decompress_vec(&body).ok()
+            .and_then(|decoded| WriteRequest::decode(decoded.as_slice()).ok())
+            .as_ref(),
+        Some(request),
+        "remote-write payload does not decode to the write request",
+    );
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{GaugeVec, Histogram, HistogramOpts, Opts};
+
+    use super::*;
+
+    fn labels(series: &TimeSeries) -> Vec<(&str, &str)> {
+        series.labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect()
+    }
+
+    #[test]
+    fn gauges_become_one_series_with_sorted_labels() {
+        let registry = Registry::new();
+        let cpu = GaugeVec::new(Opts::new("docker_cpu_percent", "CPU"), &["name", "id"]).unwrap();
+        cpu.with_label_values(&["web", "abc123"]).set(1.5);
+        registry.register(Box::new(cpu)).unwrap();
+        let request = write_request(&registry.gather(), 1700000000000);
+        assert_eq!(request.timeseries.len(), 1);
+        let series = &request.timeseries[0];
+        assert_eq!(labels(series), [("__name__", "docker_cpu_percent"), ("id", "abc123"), ("name", "web")]);
+        assert_eq!(series.samples, [RemoteSample { value: 1.5, timestamp: 1700000000000 }]);
+    }
+
+    #[test]
+    fn histograms_are_split_like_the_text_format() {
+        let registry = Registry::new();
+        let histogram = Histogram::with_opts(HistogramOpts::new("docker_cpu_percent_distribution", "CPU").buckets(vec![5.0, 10.0])).unwrap();
+        histogram.observe(7.5);
+        registry.register(Box::new(histogram)).unwrap();
+        let request = write_request(&registry.gather(), 0);
+        let series: Vec<(Vec<(&str, &str)>, f64)> = request.timeseries.iter()
+            .map(|series| (labels(series), series.samples[0].value))
+            .collect();
+        assert_eq!(series, [
+            (vec![("__name__", "docker_cpu_percent_distribution_bucket"), ("le", "5")], 0.0),
+            (vec![("__name__", "docker_cpu_percent_distribution_bucket"), ("le", "10")], 1.0),
+            (vec![("__name__", "docker_cpu_percent_distribution_bucket"), ("le", "+Inf")], 1.0),
+            (vec![("__name__", "docker_cpu_percent_distribution_count")], 1.0),
+            (vec![("__name__", "docker_cpu_percent_distribution_sum")], 7.5),
+        ]);
+    }
+
+    #[test]
+    fn the_body_decodes_to_the_request() {
+        let request = WriteRequest { timeseries: vec![TimeSeries {
+            labels: vec![Label { name: "__name__".to_string(), value: "docker_pids".to_string() }],
+            samples: vec![RemoteSample { value: 5.0, timestamp: 1 }],
+        }] };
+        let body = encode(&request).unwrap();
+        let decoded = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+        assert_eq!(WriteRequest::decode(decoded.as_slice()).unwrap(), request);
+    }
+}
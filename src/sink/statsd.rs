@@ -0,0 +1,127 @@
+//! StatsD/DogStatsD over UDP
+
+use log::warn;
+use tokio::net::UdpSocket;
+
+use crate::Sample;
+use super::{Sink, gauge_values};
+
+/// Sends every sample as DogStatsD gauges (`docker.cpu_percent:1.5|g|#name:web,id:...`) over UDP
+pub(crate) struct StatsdSink {
+    socket: UdpSocket,
+    /// Sent as additional tags, like the `--node-label` ones
+    extra_labels: Vec<String>,
+    node_labels: Vec<(String, String)>,
+}
+impl StatsdSink {
+    pub(crate) async fn connect(host: &str, port: u16, extra_labels: &[String], node_labels: &[(String, String)]) -> std::io::Result<Self> {
+        let addr = tokio::net::lookup_host((host, port)).await?.next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("cannot resolve {}", host)))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+        socket.connect(addr).await?;
+        Ok(StatsdSink { socket, extra_labels: extra_labels.to_vec(), node_labels: node_labels.to_vec() })
+    }
+}
+/// Payload budget of one datagram: an Ethernet MTU of 1500 minus IP and UDP
+/// headers, so packets aren't fragmented (or dropped by the agent)
+const MAX_PACKET: usize = 1432;
+
+/// Replace everything but the characters allowed in `--node-label` values,
+/// since `|`, `,`, `#` or a newline in a container name, image or label
+/// would end the tag (or the metric) early
+fn tag_value(s: &str) -> String {
+    match s {
+        "" => "_".to_string(),
+        s => s.chars().map(|c| if c.is_ascii_alphanumeric() || "_.:/-".contains(c) { c } else { '_' }).collect(),
+    }
+}
+
+/// All metrics of one sample as multi-metric DogStatsD packets of at most
+/// [`MAX_PACKET`] bytes (a single longer line is sent on its own)
+fn statsd_packets(sample: &Sample, extra_labels: &[String], node_labels: &[(String, String)]) -> Vec<String> {
+    let mut tags = format!("#name:{},id:{}", tag_value(&sample.name), tag_value(&sample.id));
+    for label in extra_labels {
+        let value = sample.extra_label(label);
+        if !value.is_empty() {
+            tags.push_str(&format!(",{}:{}", label, tag_value(value)));
+        }
+    }
+    for (key, value) in node_labels {
+        tags.push_str(&format!(",{}:{}", key, value));
+    }
+    let gauges = gauge_values(sample);
+    let lines = gauges.iter()
+        .map(|(metric, value)| format!("docker.{}:{}|g|{}", metric, value, tags))
+        .chain(sample.net_interfaces.iter().flat_map(|(interface, (rx, tx))| [
+            format!("docker.net_interface_input_bytes:{}|g|{},interface:{}", rx, tags, tag_value(interface)),
+            format!("docker.net_interface_output_bytes:{}|g|{},interface:{}", tx, tags, tag_value(interface)),
+        ]))
+        .chain(sample.block_devices.iter().flat_map(|(device, (read, write))| [
+            format!("docker.block_device_read_bytes:{}|g|{},device:{}", read, tags, tag_value(device)),
+            format!("docker.block_device_write_bytes:{}|g|{},device:{}", write, tags, tag_value(device)),
+        ]));
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+impl Sink for StatsdSink {
+    async fn record(&mut self, sample: &Sample) {
+        for packet in statsd_packets(sample, &self.extra_labels, &self.node_labels) {
+            if let Err(e) = self.socket.send(packet.as_bytes()).await {
+                warn!("StatsD send error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_values_cannot_end_the_tag() {
+        let mut sample = Sample { name: "we|b,x#y".to_string(), id: "abc123".to_string(), image: "nginx:1.25 alpine".to_string(), ..Sample::default() };
+        sample.mapped_labels.insert("team".to_string(), "pay\nments".to_string());
+        let packets = statsd_packets(&sample, &["image".to_string(), "team".to_string()], &[("node".to_string(), "web01".to_string())]);
+        assert_eq!(packets.len(), 1);
+        let first = packets[0].lines().next().unwrap();
+        assert_eq!(first, "docker.cpu_percent:0|g|#name:we_b_x_y,id:abc123,image:nginx:1.25_alpine,team:pay_ments,node:web01");
+        assert!(packets[0].lines().all(|line| line.matches('|').count() == 2 && line.matches('#').count() == 1));
+    }
+
+    #[test]
+    fn empty_labels_are_left_out() {
+        let sample = Sample { name: "web".to_string(), id: "abc123".to_string(), ..Sample::default() };
+        let packets = statsd_packets(&sample, &["image".to_string()], &[]);
+        assert!(packets[0].starts_with("docker.cpu_percent:0|g|#name:web,id:abc123\n"));
+    }
+
+    #[test]
+    fn packets_stay_within_the_mtu() {
+        let mut sample = Sample { name: "web".repeat(40), id: "abc123def456".to_string(), ..Sample::default() };
+        for i in 0..20 {
+            sample.net_interfaces.insert(format!("eth{}", i), (1000, 2000));
+        }
+        let packets = statsd_packets(&sample, &[], &[]);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET));
+        let lines: usize = packets.iter().map(|packet| packet.lines().count()).sum();
+        assert_eq!(lines, gauge_values(&sample).len() + 2 * 20);
+    }
+
+    #[test]
+    fn a_long_line_goes_out_alone() {
+        let sample = Sample { name: "x".repeat(2 * MAX_PACKET), id: "abc123".to_string(), ..Sample::default() };
+        let packets = statsd_packets(&sample, &[], &[]);
+        assert_eq!(packets.len(), gauge_values(&sample).len());
+    }
+}
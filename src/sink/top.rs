@@ -0,0 +1,72 @@
+//! `--once --top N`: a table of the busiest containers for reading, instead
+//! of the Prometheus exposition
+
+use std::sync::{Arc, Mutex};
+
+use crate::Sample;
+use super::Sink;
+
+/// Keeps every sample of the snapshot, [`TopSink::table`] is printed once the
+/// pipeline is done. Clones share the samples.
+#[derive(Clone, Default)]
+pub(crate) struct TopSink {
+    samples: Arc<Mutex<Vec<Sample>>>,
+}
+impl TopSink {
+    /// The `top` containers with the highest CPU (`sort_by` `cpu`) or memory
+    /// (`mem`) usage, highest first, as a table like the one of `docker stats`
+    pub(crate) fn table(&self, sort_by: &str, top: usize) -> String {
+        let mut samples = self.samples.lock().unwrap().clone();
+        match sort_by {
+            "mem" => samples.sort_by(|a, b| b.mem_usage.cmp(&a.mem_usage).then_with(|| a.name.cmp(&b.name))),
+            _ => samples.sort_by(|a, b| b.cpu.total_cmp(&a.cpu).then_with(|| a.name.cmp(&b.name))),
+        }
+        samples.truncate(top);
+
+        // The host only tells something with several docker hosts
+        let with_host = samples.iter().any(|sample| !sample.host.is_empty());
+        let name_width = samples.iter().map(|sample| sample.name.chars().count()).max().unwrap_or(0).max("NAME".len());
+        let host_width = samples.iter().map(|sample| sample.host.chars().count()).max().unwrap_or(0).max("HOST".len());
+        let mut table = String::new();
+        if with_host {
+            table.push_str(&format!("{:host_width$}  ", "HOST"));
+        }
+        table.push_str(&format!("{:name_width$}  {:>8}  {:>21}  {:>7}  {:>6}\n", "NAME", "CPU %", "MEM USAGE / LIMIT", "MEM %", "PIDS"));
+        for sample in &samples {
+            if with_host {
+                table.push_str(&format!("{:host_width$}  ", sample.host));
+            }
+            let mem = match sample.mem_limit {
+                Some(limit) => format!("{} / {}", size(sample.mem_usage), size(limit)),
+                None => size(sample.mem_usage),
+            };
+            let mem_percent = sample.mem_percent.map(|percent| format!("{:.2}%", percent)).unwrap_or_else(|| "-".to_string());
+            table.push_str(&format!(
+                "{:name_width$}  {:>8}  {:>21}  {:>7}  {:>6}\n",
+                sample.name, format!("{:.2}%", sample.cpu), mem, mem_percent, sample.pids,
+            ));
+        }
+        table
+    }
+}
+
+/// `bytes` with a binary unit, as `docker stats` shows memory
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
+
+impl Sink for TopSink {
+    async fn record(&mut self, sample: &Sample) {
+        self.samples.lock().unwrap().push(sample.clone());
+    }
+}
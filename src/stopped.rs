@@ -0,0 +1,122 @@
+//! Containers that exist but don't run, which `docker stats` leaves out.
+//! With `--include-stopped` they are listed periodically and reported as
+//! samples with all metrics at 0, marked as not running.
+
+use std::time::Duration;
+
+use log::warn;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::inspect::Source;
+use crate::{CollectorStats, Sample};
+
+/// How often the container list is refreshed when streaming (no `--interval`),
+/// well below the default stale timeout so the series don't get evicted
+const DEFAULT_REFRESH: Duration = Duration::from_secs(15);
+
+/// States without `docker stats` output. Paused and restarting containers
+/// are still reported by `docker stats`.
+const STOPPED_STATES: [&str; 3] = ["created", "exited", "dead"];
+
+/// A line of `docker ps -a --format '{{json .}}'`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PsEntry {
+    #[serde(rename = "ID")]
+    id: String,
+    /// Comma separated if the container has links
+    names: String,
+    state: String,
+}
+
+/// An entry of `GET /containers/json?all=true`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ApiContainer {
+    id: String,
+    names: Vec<String>,
+    state: String,
+}
+
+/// A stopped container, as (short id, name)
+type Stopped = (String, String);
+
+async fn list_cli(docker: &crate::DockerTarget) -> Result<Vec<Stopped>, String> {
+    let output = docker.command(&["ps", "--all", "--format", "{{json .}}"])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("failed to run docker ps: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("docker ps exited ({})", output.status));
+    }
+    let mut stopped = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines().filter(|l| !l.trim().is_empty()) {
+        let entry: PsEntry = serde_json::from_str(line).map_err(|e| format!("unparseable docker ps output: {}", e))?;
+        if STOPPED_STATES.contains(&entry.state.as_str()) {
+            let name = entry.names.split(',').next().unwrap_or_default().to_string();
+            stopped.push((entry.id.chars().take(12).collect(), name));
+        }
+    }
+    Ok(stopped)
+}
+
+async fn list_api(endpoint: &crate::api::Endpoint) -> Result<Vec<Stopped>, String> {
+    let containers: Vec<ApiContainer> = endpoint.get_json("/containers/json?all=true").await.map_err(|e| e.to_string())?;
+    Ok(containers.into_iter()
+        .filter(|c| STOPPED_STATES.contains(&c.state.as_str()))
+        .map(|c| {
+            let name = c.names.first().map(|n| n.trim_start_matches('/').to_string()).unwrap_or_default();
+            (c.id.chars().take(12).collect(), name)
+        })
+        .collect())
+}
+
+/// List the stopped containers of `source` and forward them to `tx` as
+/// samples of `host`. Returns whether they could be listed, or `None` once
+/// `tx` is closed.
+async fn forward_stopped(source: &Source, host: &str, tx: &mpsc::Sender<Sample>, stats: &CollectorStats) -> Option<bool> {
+    let listed = match source {
+        Source::Cli(docker) => list_cli(docker).await,
+        Source::Api(endpoint) => list_api(endpoint).await,
+    };
+    match listed {
+        Ok(stopped) => {
+            for (id, name) in stopped {
+                tx.send(Sample { name, id, host: host.to_string(), stopped: true, ..Sample::default() }).await.ok()?;
+            }
+            Some(true)
+        }
+        Err(e) => {
+            stats.scrape_errors.inc();
+            warn!("Listing stopped containers{} failed: {}", on_host(host), e);
+            Some(false)
+        }
+    }
+}
+
+fn on_host(host: &str) -> String {
+    if host.is_empty() { String::new() } else { format!(" on {}", host) }
+}
+
+/// Report the stopped containers of `source` every `interval` (every 15s when streaming)
+pub(crate) async fn supervise_stopped(
+    source: Source,
+    host: String,
+    tx: mpsc::Sender<Sample>,
+    interval: Option<Duration>,
+    stats: CollectorStats,
+) {
+    loop {
+        if forward_stopped(&source, &host, &tx, &stats).await.is_none() {
+            return;
+        }
+        tokio::time::sleep(interval.unwrap_or(DEFAULT_REFRESH)).await;
+    }
+}
+
+/// Report the stopped containers once for `--once`. Returns whether they could be listed.
+pub(crate) async fn collect_once(source: Source, host: String, tx: mpsc::Sender<Sample>, stats: CollectorStats) -> bool {
+    forward_stopped(&source, &host, &tx, &stats).await.unwrap_or(true)
+}